@@ -108,3 +108,24 @@ pub fn fail_bounds_check(file: *u8, line: uint, index: uint, len: uint) {
     unsafe { zero::abort(); }
 }
 */
+
+/// Mask IRQs, returning the previous CPSR for `restore`.
+#[inline]
+pub fn disable() -> u32 {
+    unsafe {
+        let cpsr: u32;
+        asm!("mrs $0, cpsr
+              orr r1, $0, #0x80
+              msr cpsr_c, r1"
+            : "=r"(cpsr) :: "r1", "cpsr" : "volatile");
+        cpsr
+    }
+}
+
+/// Put back the IRQ mask saved by `disable`.
+#[inline]
+pub fn restore(cpsr: u32) {
+    unsafe {
+        asm!("msr cpsr_c, $0" :: "r"(cpsr) : "cpsr" : "volatile");
+    }
+}
@@ -7,8 +7,8 @@ use kernel::mm::physical::Phys;
 
 pub type Frame = [u8, ..PAGE_SIZE];
 
-static PAGE_SIZE: uint = 0x1000;
-static PAGE_SIZE_LOG2: uint = 12;
+pub static PAGE_SIZE: uint = 0x1000;
+pub static PAGE_SIZE_LOG2: uint = 12;
 
 define_flags!(Flags: u32 {
     SECTION = 0b10010,
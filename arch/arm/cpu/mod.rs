@@ -1,5 +1,6 @@
 pub mod interrupt;
 pub mod mmu;
+pub mod sync;
 
 pub fn init() {
     /* unsafe { */
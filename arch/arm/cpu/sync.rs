@@ -0,0 +1,39 @@
+use core::mem::volatile_store;
+
+use cpu::interrupt;
+
+/// Lock word spun on with `swp`, taken with IRQs masked so a handler
+/// can't deadlock against the code it interrupted.
+pub struct Spinlock {
+    // 0 is unlocked
+    locked: u32
+}
+
+impl Spinlock {
+    pub fn new() -> Spinlock {
+        Spinlock { locked: 0 }
+    }
+
+    /// Spin until the lock is ours. Returns the CPSR to pass to `unlock`.
+    pub fn lock(&self) -> u32 {
+        let cpsr = interrupt::disable();
+        let word = &self.locked as *u32 as *mut u32;
+        loop {
+            let old: u32;
+            unsafe {
+                asm!("swp $0, $1, [$2]"
+                    : "=&r"(old) : "r"(1u32), "r"(word) : "memory" : "volatile");
+            }
+            if old == 0 {
+                return cpsr;
+            }
+        }
+    }
+
+    pub fn unlock(&self, cpsr: u32) {
+        unsafe {
+            volatile_store(&self.locked as *u32 as *mut u32, 0);
+        }
+        interrupt::restore(cpsr);
+    }
+}
@@ -0,0 +1,73 @@
+// rust-core's names for the std items the kernel's memory managers use,
+// so that they build on the host. Aborts fail the task instead of halting,
+// which lets `#[should_fail]` tests catch them.
+
+pub mod fail {
+    pub fn abort() -> ! {
+        fail!("abort")
+    }
+
+    pub fn assert(x: bool) {
+        if !x {
+            fail!("assertion failed")
+        }
+    }
+}
+
+pub mod i32 {
+    pub use std::intrinsics::{ctlz32, cttz32};
+}
+
+pub mod i64 {
+    pub use std::intrinsics::{ctlz64, cttz64};
+}
+
+pub mod iter {
+    pub use std::iter::Iterator;
+}
+
+pub mod mem {
+    pub use std::cast::transmute;
+    pub use std::mem::{size_of, min_align_of};
+}
+
+pub mod ops {
+    pub use std::ops::Drop;
+}
+
+pub mod option {
+    pub use std::option::{Option, Some, None};
+}
+
+pub mod ptr {
+    pub use std::ptr::{set_memory, copy_memory, offset};
+}
+
+pub mod result {
+    pub use std::result::{Result, Ok, Err};
+}
+
+pub mod slice {
+    use std::slice::Items;
+
+    pub fn iter<'a, T>(v: &'a [T]) -> Items<'a, T> {
+        v.iter()
+    }
+}
+
+pub mod str {
+    pub fn as_bytes<'a>(s: &'a str) -> &'a [u8] {
+        s.as_bytes()
+    }
+}
+
+pub mod uint {
+    use std::num::CheckedMul;
+
+    pub fn mul_with_overflow(x: uint, y: uint) -> (uint, bool) {
+        match x.checked_mul(&y) {
+            Some(product) => (product, false),
+            None => (x * y, true)
+        }
+    }
+}
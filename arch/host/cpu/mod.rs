@@ -0,0 +1,8 @@
+// Only what the memory managers import from the CPU
+
+pub mod sync;
+
+pub mod mmu {
+    pub static PAGE_SIZE: uint = 0x1000;
+    pub static PAGE_SIZE_LOG2: uint = 12;
+}
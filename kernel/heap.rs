@@ -1,38 +1,60 @@
-use core::fail::out_of_memory;
+use core::fail::{out_of_memory, assert};
 use core::mem::size_of;
 use core::uint::mul_with_overflow;
+use core::option::None;
 
-use kernel::mm::{Allocator, Alloc, BuddyAlloc};
+use cpu::sync::Spinlock;
+use kernel::mm::{Allocator, Alloc, GlobalBuddy, LockedAllocator};
+use kernel::mm::{bitv_bytes_for_order, MAX_PADDED};
 use util::bitv;
+use util::cell::SharedCell;
 
-pub static mut heap: Alloc = Alloc {
-    base: 0x110_000 as *mut u8,
-    el_size: 0,
-    parent: BuddyAlloc {
-        order: 17,
-        tree: bitv::Bitv { storage: 0x100_000 as *mut u32 }
+// Statics can only be built from struct literals, so the types making up
+// this one and `physical::frames` leave their fields public rather than
+// `priv`, down to the `Spinlock` and `SharedCell`.
+pub static mut heap: GlobalBuddy = GlobalBuddy {
+    heap: LockedAllocator {
+        lock: Spinlock { locked: 0 },
+        inner: SharedCell {
+            value: Alloc {
+                base: 0x110_000 as *mut u8,
+                el_size: 0,
+                canaries: 0 as *mut uint,
+                dirty: 0 as *mut u32,
+                oom_handler: None,
+                padded: [(0, 0), ..MAX_PADDED],
+                padded_count: 0,
+                parent: buddy_alloc_static!(17, 0x100_000)
+            }
+        }
     }
 };
 
 pub fn init() {
     unsafe {
-        heap.parent.tree.clear(1 << (heap.parent.order + 1));
+        heap.with(|a| {
+            // The tree lives in the gap below the heap
+            let room = a.base as uint - a.parent.tree.storage as uint;
+            assert(room >= bitv_bytes_for_order(a.parent.order));
+            a.parent.tree.clear(bitv::Bitv::nodes_for_order(a.parent.order));
+        });
     }
 }
 
 #[lang = "exchange_malloc"]
 #[inline]
 pub unsafe fn malloc_raw(size: uint) -> *mut u8 {
-    match heap.alloc(size) {
-        (_, 0) => out_of_memory(),
-        (ptr, _) => ptr
+    match heap.allocate(size, 1) {
+        p if p as uint == 0 && size != 0 => out_of_memory(),
+        p => p
     }
 }
 
 #[lang = "exchange_free"]
 #[inline]
 pub unsafe fn free<T>(ptr: *mut T) {
-    heap.free(ptr as *mut u8);
+    // No size is passed here, so the block is found from the pointer
+    heap.with(|a| a.free(ptr as *mut u8));
 }
 
 #[inline]
@@ -47,8 +69,8 @@ pub unsafe fn alloc<T = u8>(count: uint) -> *mut T {
 pub unsafe fn zero_alloc<T = u8>(count: uint) -> *mut T {
     match mul_with_overflow(count, size_of::<T>()) {
         (_, true) => out_of_memory(),
-        (size, _) => match heap.zero_alloc(size) {
-            (_, 0) => out_of_memory(),
+        (size, _) => match heap.with(|a| a.zero_alloc(size)) {
+            (_, 0) if size != 0 => out_of_memory(),
             (ptr, _) => ptr as *mut T
         }
     }
@@ -62,7 +84,7 @@ pub unsafe fn realloc_raw<T>(ptr: *mut T, count: uint) -> *mut T {
             free(ptr);
             0 as *mut T
         }
-        (size, _) => match heap.realloc(ptr as *mut u8, size) {
+        (size, _) => match heap.with(|a| a.realloc(ptr as *mut u8, size)) {
             (_, 0) => out_of_memory(),
             (ptr, _) => ptr as *mut T
         }
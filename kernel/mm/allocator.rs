@@ -1,166 +1,941 @@
-use core::mem::transmute;
+use core::mem::{transmute, size_of, min_align_of};
 use core::ptr::{set_memory, copy_memory, offset};
-use core::i32::ctlz32;
+use core::i32::{ctlz32, cttz32};
+use core::fail::{abort, assert};
+use core::result::{Result, Ok, Err};
+use core::option::{Option, Some, None};
+use core::iter::Iterator;
+use core::slice;
+use core::uint::mul_with_overflow;
 
+use cpu::mmu::PAGE_SIZE;
 use util::ptr::mut_offset;
 use util::bitv::Bitv;
+#[cfg(not(ndebug))]
+use util::fmt::{Writer, Show};
+#[cfg(not(ndebug))]
+use util::serial::Serial;
+
+pub static CACHE_LINE_SIZE: uint = 64;
+/// Byte written over freed blocks when poisoning is on
+pub static POISON_BYTE: u8 = 0xDE;
+/// Word written after each allocation when canaries are on
+pub static CANARY: u32 = 0xC0DEFACE;
+// Per-level tables cover trees up to order MAX_LEVELS - 1. A tree of
+// order 30 has 2^31 - 1 nodes; one more level and its node count, and
+// `1 << (order + 1)`, would overflow a 32-bit uint.
+pub static MAX_LEVELS: uint = 31;
+/// Ranges one tree can hold reserved
+pub static MAX_RESERVED: uint = 16;
+/// Blocks one tree can hold pinned
+pub static MAX_PINNED: uint = 16;
+/// Blocks one `Alloc` can hand out padded for alignment at once
+pub static MAX_PADDED: uint = 16;
+/// What `Alloc` returns for zero-sized requests aligned to at most 16
+/// bytes; see `zero_size_ptr`. It must not be dereferenced.
+pub static ZERO_SIZE_PTR: *mut u8 = 0x10 as *mut u8;
+/// End of a free list
+pub static NIL: uint = !0;
 
 #[repr(u8)]
-enum Node {
+pub enum Node {
     UNUSED = 0,
     USED = 1,
     SPLIT = 2,
     FULL = 3
 }
 
+// Links of a block on its level's free list, as unit offsets. They are
+// kept in the first words of the free block itself.
+struct FreeLink {
+    next: uint,
+    prev: uint
+}
+
+/// Which half of a node `BuddyAlloc` searches and splits into first.
+/// `LeftFirst` packs allocations at low offsets; `RightFirst` at high
+/// ones, keeping the low end free in large blocks.
+pub enum SplitPolicy {
+    LeftFirst,
+    RightFirst
+}
+
+#[inline]
+fn first_child(policy: SplitPolicy, index: uint) -> uint {
+    match policy {
+        LeftFirst => index * 2 + 1,
+        RightFirst => index * 2 + 2
+    }
+}
+
+// The sibling of `index` visited after it, if it hasn't been yet
+#[inline]
+fn other_sibling(policy: SplitPolicy, index: uint) -> Option<uint> {
+    match policy {
+        LeftFirst if index & 1 == 1 => Some(index + 1),
+        RightFirst if index != 0 && index & 1 == 0 => Some(index - 1),
+        _ => None
+    }
+}
+
+// The other child of `index`'s parent
+#[inline]
+fn buddy_of(index: uint) -> uint {
+    index - 1 + (index & 1) * 2
+}
+
+// smallest `n` such that `1 << n >= x`
+#[inline]
+fn lg2_ceil(x: uint) -> uint {
+    if x <= 1 { 0 } else { 32 - unsafe { ctlz32(x as i32 - 1) } as uint }
+}
+
+// Largest alignment of a zero-sized request, and the size of the region
+// at the top of the address space its pointers come from
+static MAX_ZERO_SIZE_ALIGN: uint = 1 << 20;
+
+/// The dangling pointer `Alloc` returns for a zero-sized request aligned
+/// to `align`: `ZERO_SIZE_PTR`, or `align` itself up to a page. The first
+/// page has no address aligned to a page or more, so those alignments, up
+/// to 1 MiB, get the highest address aligned to them instead. Arenas must
+/// cover neither the first page nor the last MiB of the address space, so
+/// that `free`, `free_checked` and `realloc` recognize these pointers by
+/// address with `is_zero_size`.
+pub fn zero_size_ptr(align: uint) -> *mut u8 {
+    assert(align <= MAX_ZERO_SIZE_ALIGN);
+    if align >= PAGE_SIZE {
+        !(align - 1) as *mut u8
+    }
+    else if align > ZERO_SIZE_PTR as uint {
+        align as *mut u8
+    }
+    else {
+        ZERO_SIZE_PTR
+    }
+}
+
+/// Whether `ptr` is a pointer `zero_size_ptr` can return: anything in the
+/// first page but null, or in the last MiB of the address space.
+#[inline]
+pub fn is_zero_size(ptr: *mut u8) -> bool {
+    let addr = ptr as uint;
+    addr != 0 && (addr < PAGE_SIZE || addr >= !(MAX_ZERO_SIZE_ALIGN - 1))
+}
+
+// Level of the block `alloc_aligned` gives for `size` units at `align`
+fn level_for(size: uint, align: uint) -> uint {
+    assert(align != 0 && align & (align - 1) == 0);
+    let lg2_size = lg2_ceil(if size == 0 { 1 } else { size });
+    let lg2_align = 31 - unsafe { ctlz32(align as i32) } as uint;
+    if lg2_align > lg2_size { lg2_align } else { lg2_size }
+}
+
+/// Bytes of `Bitv` storage a tree of `order` needs: two bits for each of
+/// its `2^(order+1) - 1` nodes, rounded up to whole words.
+pub fn bitv_bytes_for_order(order: uint) -> uint {
+    Bitv::bytes_for_order(order)
+}
+
+/// An entry of a firmware memory map: `size` bytes from `start`, which
+/// may be handed out only if `usable`.
+pub struct MemoryRegion {
+    start: *mut u8,
+    size: uint,
+    usable: bool
+}
+
+pub enum AllocError {
+    OutOfMemory,
+    SizeTooLarge,
+    /// `MAX_PADDED` blocks padded for alignment off an unaligned base are
+    /// already out; see `Alloc::new`.
+    TooManyPadded
+}
+
+pub enum FreeError {
+    NotOwned,
+    DoubleFree,
+    InteriorPointer,
+    Reserved
+}
+
+pub enum EventOp {
+    Allocated,
+    Freed
+}
+
+/// An entry of the log kept by `BuddyAlloc::record_events`. `seq` counts
+/// events from the first one logged; `size` is in bytes, and for frees
+/// is that of the whole block.
+pub struct AllocEvent {
+    seq: uint,
+    op: EventOp,
+    ptr: *mut u8,
+    size: uint
+}
+
+/// A successful allocation: `size` usable bytes at `ptr`, which is a
+/// multiple of `align`. `align` is the largest power of two dividing
+/// `ptr`, so it may exceed the alignment asked for.
+pub struct AllocResult {
+    ptr: *mut u8,
+    size: uint,
+    align: uint
+}
+
 pub trait Allocator {
-    fn alloc(&mut self, size: uint) -> (*mut u8, uint);
+    /// Deprecated: use `alloc_result`. Out of memory is a zero size here.
+    fn alloc(&mut self, size: uint) -> (*mut u8, uint) {
+        unsafe { self.alloc_align(size, 1) }
+    }
 
-    fn zero_alloc(&mut self, s: uint) -> (*mut u8, uint) {
-        let (ptr, size) = self.alloc(s);
-        unsafe { set_memory(ptr, 0, size); }
-        (ptr, size)
+    /// Allocate a block whose base is a multiple of `align` bytes. `align`
+    /// must be a power of two. Buddy allocators also align each block to
+    /// its own size relative to the arena base, so with a base aligned to
+    /// the arena's size, a block is aligned to the size returned for it.
+    ///
+    /// Deprecated for callers, who should use `alloc_result`; allocators
+    /// still implement this, and the named form is built on it.
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint);
+
+    /// `alloc_align` with the result named, and out of memory as `None`
+    /// rather than a zero size. This replaces the deprecated tuple methods,
+    /// which stay only for existing callers.
+    unsafe fn alloc_result(&mut self, size: uint, align: uint) -> Option<AllocResult> {
+        match self.alloc_align(size, align) {
+            (_, 0) if size != 0 => None,
+            (ptr, sz) => Some(AllocResult {
+                ptr: ptr,
+                size: sz,
+                align: ptr as uint & (!(ptr as uint) + 1)
+            })
+        }
     }
 
-    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
-        self.free(src);
-        let (ptr, sz) = self.alloc(size);
-        unsafe { copy_memory(ptr, src as *u8, sz); }
-        (ptr, sz)
+    /// Deprecated: use `alloc_result`.
+    fn try_alloc(&mut self, size: uint) -> Result<(*mut u8, uint), AllocError> {
+        match self.alloc(size) {
+            (_, 0) => Err(OutOfMemory),
+            r => Ok(r)
+        }
+    }
+
+    /// Deprecated: use `alloc_result` and clear the block.
+    fn zero_alloc(&mut self, s: uint) -> (*mut u8, uint) {
+        match self.alloc(s) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, size) => {
+                unsafe { set_memory(ptr, 0, size); }
+                (ptr, size)
+            }
+        }
     }
 
+    /// Resize the block at `src` to `size`, moving it if needed and
+    /// copying no more than the old block holds. A null `src` is a plain
+    /// allocation and a zero `size` frees `src`, returning null. On failure
+    /// `src` is left allocated and untouched. There is no default, since
+    /// only the allocator knows how large `src`'s block is.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint);
+
+    /// Release a block. Freeing null is always a no-op, so owners may free
+    /// whether or not their allocation ever happened.
     fn free(&mut self, ptr: *mut u8);
+
+    /// Whether `ptr` lies in memory this allocator hands out. Combinators
+    /// use this to route `free` back to the allocator a block came from.
+    fn owns(&self, ptr: *mut u8) -> bool;
+
+    /// Bytes that may be used from `ptr`, a pointer this allocator handed
+    /// out, to the end of its block, or 0 if it isn't a live allocation.
+    /// This bounds the copy when a block moves to another allocator.
+    fn usable_size(&self, ptr: *mut u8) -> uint;
 }
 
 pub struct BuddyAlloc {
     order: uint,
-    tree: Bitv
+    tree: Bitv,
+    used: uint,
+    peak_used: uint,
+    alloc_count: uint,
+    // see `set_poison_on_free`
+    poison_on_free: bool,
+    // see `new`
+    zero_on_free: bool,
+    // ranges set aside by `reserve` as (start, end) in units
+    reserved: [(uint, uint), ..MAX_RESERVED],
+    reserved_count: uint,
+    // offsets of the blocks allocated by `alloc_pinned`; their sizes are
+    // read from the tree, so resizing in place keeps them right
+    pinned: [uint, ..MAX_PINNED],
+    pinned_count: uint,
+    // see `set_on_alloc` and `set_on_free`
+    on_alloc: Option<fn(*mut u8, uint)>,
+    on_free: Option<fn(*mut u8)>,
+    // see `record_events`
+    event_log: *mut AllocEvent,
+    event_capacity: uint,
+    event_count: uint,
+    // bit `l` is set if a node at level `l` may be unused; see `find`
+    free_levels: uint,
+    // see `set_split_policy`
+    split_policy: SplitPolicy,
+    // units from here on haven't been handed out since `assume_zeroed`
+    fresh_from: uint,
+    // see `Alloc::set_free_lists`: the arena's memory, null while the
+    // lists are off, and the unit size in it
+    links: *mut u8,
+    link_shift: uint,
+    // lowest level whose blocks can hold a `FreeLink`
+    list_from: uint,
+    // offset of the first block on each level's free list, or `NIL`
+    free_heads: [uint, ..MAX_LEVELS]
 }
 
 pub struct Alloc {
     parent: BuddyAlloc,
     base: *mut u8,
-    el_size: uint
+    el_size: uint,
+    // see `set_canaries`
+    canaries: *mut uint,
+    // see `set_dirty_map`
+    dirty: *mut u32,
+    // see `set_oom_handler`
+    oom_handler: Option<fn(&mut Alloc, uint) -> bool>,
+    // (offset, bytes skipped) of the blocks `alloc_opt` handed out past
+    // their start to align them
+    padded: [(uint, uint), ..MAX_PADDED],
+    padded_count: uint
+}
+
+/// Runs of adjacent blocks in the same state, in address order, as
+/// (offset, size, state) in units. Only UNUSED and USED occur.
+pub struct Extents<'a> {
+    priv alloc: &'a BuddyAlloc,
+    priv index: uint,
+    priv level: uint,
+    priv offset: uint,
+    priv done: bool,
+    priv pending: Option<(uint, uint, Node)>
+}
+
+/// The logged events of a `BuddyAlloc`, oldest first.
+pub struct Events<'a> {
+    priv alloc: &'a BuddyAlloc,
+    priv next: uint
+}
+
+/// `Extents` of an `Alloc` as (pointer, size in bytes, state).
+pub struct MemoryMap<'a> {
+    priv extents: Extents<'a>,
+    priv base: *mut u8,
+    priv el_size: uint
+}
+
+/// Each allocated block of an `Alloc` in address order as (pointer to the
+/// block's start, size in bytes). Reserved ranges are left out.
+pub struct LiveAllocations<'a> {
+    priv extents: Extents<'a>,
+    priv base: *mut u8,
+    priv el_size: uint
 }
 
 impl BuddyAlloc {
-    pub fn new(order: uint, storage: Bitv) -> BuddyAlloc {
-        storage.clear(1 << (order + 1));
-        BuddyAlloc { order: order, tree: storage }
+    /// Build an empty tree of `order` in `storage`, which must hold
+    /// `storage_bytes` bytes, at least `bitv_bytes_for_order(order)`.
+    ///
+    /// With `zero_on_free`, blocks freed through an `Alloc` are zeroed
+    /// before they are reclaimed, for arenas holding secrets. That costs a
+    /// write of every byte of every freed block, the same as zeroing on
+    /// allocation, and overrides `set_poison_on_free`.
+    pub fn new(order: uint, mut storage: Bitv, storage_bytes: uint,
+               zero_on_free: bool) -> BuddyAlloc {
+        assert(order < MAX_LEVELS);
+        assert(storage_bytes >= bitv_bytes_for_order(order));
+        storage.clear(Bitv::nodes_for_order(order));
+        BuddyAlloc {
+            order: order,
+            tree: storage,
+            used: 0,
+            peak_used: 0,
+            alloc_count: 0,
+            poison_on_free: false,
+            zero_on_free: zero_on_free,
+            reserved: [(0, 0), ..MAX_RESERVED],
+            reserved_count: 0,
+            pinned: [0, ..MAX_PINNED],
+            pinned_count: 0,
+            on_alloc: None,
+            on_free: None,
+            event_log: 0 as *mut AllocEvent,
+            event_capacity: 0,
+            event_count: 0,
+            free_levels: !0,
+            split_policy: LeftFirst,
+            fresh_from: !0,
+            links: 0 as *mut u8,
+            link_shift: 0,
+            list_from: 0,
+            free_heads: [NIL, ..MAX_LEVELS]
+        }
+    }
+
+    /// Levels above the leaves; the largest block is `1 << order` units.
+    pub fn order(&self) -> uint {
+        self.order
+    }
+
+    /// Units in the whole tree, `1 << order`
+    pub fn capacity(&self) -> uint {
+        1 << self.order
+    }
+
+    /// Units currently allocated, counting each block at its full size.
+    pub fn used(&self) -> uint {
+        self.used
+    }
+
+    /// Highest value `used` has reached since creation or `reset_peak`.
+    pub fn peak_used(&self) -> uint {
+        self.peak_used
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak_used = self.used;
+    }
+
+    /// Number of blocks currently allocated. Growing or relocating a block
+    /// in `realloc` leaves this unchanged.
+    pub fn allocation_count(&self) -> uint {
+        self.alloc_count
+    }
+
+    /// Units not currently allocated. Free space may be fragmented, so
+    /// this can exceed the largest block that can actually be allocated.
+    pub fn unused(&self) -> uint {
+        (1 << self.order) - self.used
+    }
+
+    /// Size in units of the largest unused block, or 0 when full.
+    pub fn largest_free(&self) -> uint {
+        self.largest_under(0, self.order)
+    }
+
+    /// Number of unused blocks at each level.
+    pub fn free_counts(&self) -> [uint, ..MAX_LEVELS] {
+        let mut counts = [0u, ..MAX_LEVELS];
+        self.count_free(0, self.order, &mut counts);
+        counts
+    }
+
+    fn count_free(&self, index: uint, level: uint, counts: &mut [uint, ..MAX_LEVELS]) {
+        match self.get(index) {
+            UNUSED => counts[level] += 1,
+            SPLIT => {
+                self.count_free(index * 2 + 1, level - 1, counts);
+                self.count_free(index * 2 + 2, level - 1, counts);
+            }
+            _ => {}
+        }
+    }
+
+    /// Number of allocated blocks at each level, leaving out reserved
+    /// ranges.
+    pub fn size_histogram(&self) -> [uint, ..MAX_LEVELS] {
+        let mut counts = [0u, ..MAX_LEVELS];
+        self.count_used(0, self.order, 0, &mut counts);
+        counts
+    }
+
+    fn count_used(&self, index: uint, level: uint, left: uint,
+                  counts: &mut [uint, ..MAX_LEVELS]) {
+        match self.get(index) {
+            USED if !self.is_reserved(left, 1 << level) => counts[level] += 1,
+            SPLIT | FULL => {
+                self.count_used(index * 2 + 1, level - 1, left, counts);
+                self.count_used(index * 2 + 2, level - 1, left + (1 << (level - 1)), counts);
+            }
+            _ => {}
+        }
+    }
+
+    /// External fragmentation in basis points: 0 when all free space is
+    /// one block, approaching 10000 as it is scattered into small ones.
+    pub fn fragmentation_ratio(&self) -> uint {
+        let free = self.unused();
+        if free == 0 {
+            return 0;
+        }
+        // u64 so that large arenas don't overflow
+        10000 - (self.largest_free() as u64 * 10000 / free as u64) as uint
+    }
+
+    // Nodes under a used or unused node are stale, so only descend
+    // through SPLIT nodes.
+    fn largest_under(&self, index: uint, level: uint) -> uint {
+        match self.get(index) {
+            UNUSED => 1 << level,
+            SPLIT => {
+                let left = self.largest_under(index * 2 + 1, level - 1);
+                if left == 1 << (level - 1) {
+                    return left;
+                }
+                let right = self.largest_under(index * 2 + 2, level - 1);
+                if left > right { left } else { right }
+            }
+            _ => 0
+        }
+    }
+
+    /// Install a hook called with the pointer and size in bytes of each
+    /// allocation made through an `Alloc`, once the tree is updated. It is
+    /// not called when an allocation fails, nor for blocks resized in place.
+    pub fn set_on_alloc(&mut self, hook: Option<fn(*mut u8, uint)>) {
+        self.on_alloc = hook;
+    }
+
+    /// Install a hook called with the pointer passed to each free through
+    /// an `Alloc`, once the block is back in the tree.
+    pub fn set_on_free(&mut self, hook: Option<fn(*mut u8)>) {
+        self.on_free = hook;
+    }
+
+    /// Log the allocations and frees made through an `Alloc` in `buffer`,
+    /// which must hold `capacity` events, overwriting the oldest once it
+    /// is full. Pass a capacity of 0 to stop logging. The log is kept in
+    /// place, so it survives to be read back after a crash.
+    pub unsafe fn record_events(&mut self, buffer: *mut AllocEvent, capacity: uint) {
+        self.event_log = buffer;
+        self.event_capacity = capacity;
+        self.event_count = 0;
+    }
+
+    /// The events still in the log, oldest first.
+    pub fn events<'a>(&'a self) -> Events<'a> {
+        let first = if self.event_count > self.event_capacity {
+            self.event_count - self.event_capacity
+        }
+        else {
+            0
+        };
+        Events { alloc: self, next: first }
+    }
+
+    fn log_event(&mut self, op: EventOp, ptr: *mut u8, size: uint) {
+        if self.event_capacity == 0 {
+            return;
+        }
+        unsafe {
+            let slot = mut_offset(self.event_log, (self.event_count % self.event_capacity) as int);
+            *slot = AllocEvent { seq: self.event_count, op: op, ptr: ptr, size: size };
+        }
+        self.event_count += 1;
+    }
+
+    /// Choose which end of the arena allocations are packed toward.
+    /// `LeftFirst`, the default, keeps addresses low. Changing it doesn't
+    /// move existing blocks.
+    pub fn set_split_policy(&mut self, policy: SplitPolicy) {
+        self.split_policy = policy;
+    }
+
+    /// Overwrite blocks with `POISON_BYTE` as they are freed through an
+    /// `Alloc`, so stale reads of freed memory stand out. Off by default,
+    /// since it touches every byte of every freed block.
+    pub fn set_poison_on_free(&mut self, poison: bool) {
+        self.poison_on_free = poison;
     }
 
     #[inline]
     fn offset(&self, index: uint, level: uint) -> uint {
-        (index + 1 - (1 << self.order >> level)) << level
+        let offset = (index + 1 - (1 << self.order >> level)) << level;
+        if cfg!(not(ndebug)) {
+            assert(offset < 1 << self.order);
+        }
+        offset
     }
 
-    fn alloc(&mut self, mut size: uint) -> (uint, uint) {
-        if size == 0 {
-            size = 1;
+    // Index of the node at `level` starting at `offset`
+    #[inline]
+    fn index_at(&self, offset: uint, level: uint) -> uint {
+        (1 << (self.order - level)) - 1 + (offset >> level)
+    }
+
+    // Start keeping free lists in the arena at `links`, whose units are
+    // `1 << link_shift` bytes, with every live unused node listed.
+    fn start_lists(&mut self, links: *mut u8, link_shift: uint) {
+        self.stop_lists();
+        self.links = links;
+        self.link_shift = link_shift;
+        let mut level = 0;
+        while 1 << (level + link_shift) < size_of::<FreeLink>() {
+            level += 1;
         }
-        // smallest power of 2 >= size
-        let lg2_size = 32 - unsafe { ctlz32(size as i32 - 1) } as uint;
+        self.list_from = level;
+        self.list_under(0, self.order, 0);
+    }
 
-        let mut index = 0; // points to current tree node
-        let mut level = self.order; // current height
+    // List the live unused nodes in the subtree at `index` on `level`,
+    // which starts at `left`.
+    fn list_under(&mut self, index: uint, level: uint, left: uint) {
+        match self.get(index) {
+            UNUSED => self.push_free(left, level),
+            SPLIT => {
+                self.list_under(index * 2 + 1, level - 1, left);
+                self.list_under(index * 2 + 2, level - 1, left + (1 << (level - 1)));
+            }
+            _ => {}
+        }
+    }
 
-        loop {
-            match (self.get(index), level == lg2_size) {
-                (UNUSED, true) => {
-                    // Found appropriate unused node
-                    self.set(index, USED); // use
+    // Empty the free lists, clearing their links out of the arena, and
+    // stop keeping them.
+    fn stop_lists(&mut self) {
+        let mut level = 0;
+        while level <= self.order {
+            while self.listed(level) && self.free_heads[level] != NIL {
+                let head = self.free_heads[level];
+                self.unlink_free(head, level);
+            }
+            level += 1;
+        }
+        self.links = 0 as *mut u8;
+    }
 
-                    let mut parent = index;
-                    loop {
-                        let buddy = parent - 1 + (parent & 1) * 2;
-                        match self.get(buddy) {
-                            USED | FULL if parent > 0 => {
-                                parent = (parent + 1) / 2 - 1;
-                                self.set(parent, FULL);
-                            }
-                            _ => break
-                        }
-                    }
-                    return (
-                        self.offset(index, level),
-                        1 << lg2_size
-                    );
+    // Whether unused nodes at `level` are kept on a free list
+    #[inline]
+    fn listed(&self, level: uint) -> bool {
+        self.links as uint != 0 && level >= self.list_from
+    }
+
+    #[inline]
+    fn link(&self, offset: uint) -> *mut FreeLink {
+        (self.links as uint + (offset << self.link_shift)) as *mut FreeLink
+    }
+
+    // Put the block at `offset`, a live unused node at `level`, on the
+    // front of its level's free list.
+    fn push_free(&mut self, offset: uint, level: uint) {
+        if !self.listed(level) {
+            return;
+        }
+        let head = self.free_heads[level];
+        unsafe {
+            *self.link(offset) = FreeLink { next: head, prev: NIL };
+            if head != NIL {
+                (*self.link(head)).prev = offset;
+            }
+        }
+        self.free_heads[level] = offset;
+    }
+
+    // Take the listed block at `offset` off its level's free list. Its
+    // links are cleared, so that memory known to be zero stays zero.
+    fn unlink_free(&mut self, offset: uint, level: uint) {
+        if !self.listed(level) {
+            return;
+        }
+        unsafe {
+            let link = self.link(offset);
+            let (next, prev) = ((*link).next, (*link).prev);
+            if prev == NIL {
+                self.free_heads[level] = next;
+            }
+            else {
+                (*self.link(prev)).next = next;
+            }
+            if next != NIL {
+                (*self.link(next)).prev = prev;
+            }
+            *self.link(offset) = FreeLink { next: 0, prev: 0 };
+        }
+    }
+
+    fn alloc(&mut self, size: uint) -> Option<(uint, uint)> {
+        self.alloc_aligned(size, 1)
+    }
+
+    /// Allocate a block whose offset is a multiple of `align`. A block at
+    /// level `l` always starts at a multiple of `1 << l`, so an alignment
+    /// above the rounded-up size only promotes the search to a higher level.
+    /// The returned size is that of the promoted block.
+    pub fn alloc_aligned(&mut self, size: uint, align: uint) -> Option<(uint, uint)> {
+        let lg2_size = level_for(size, align);
+        if lg2_size > self.order {
+            // No amount of reclaiming would make this fit
+            return None;
+        }
+
+        self.find(lg2_size, true)
+    }
+
+    /// `alloc_aligned`, telling a request larger than the whole tree,
+    /// `SizeTooLarge`, apart from `OutOfMemory`, which may pass once
+    /// blocks are freed.
+    pub fn try_alloc_aligned(&mut self, size: uint,
+                             align: uint) -> Result<(uint, uint), AllocError> {
+        if level_for(size, align) > self.order {
+            return Err(SizeTooLarge);
+        }
+        match self.alloc_aligned(size, align) {
+            Some(r) => Ok(r),
+            None => Err(OutOfMemory)
+        }
+    }
+
+    // Reserve an unused block at level `lg2_size`. `free_levels` is kept
+    // a superset of the levels with a live unused node: `set` adds a level
+    // whenever a node there becomes unused, and a level is only dropped
+    // here once a search finds nothing at it. So the smallest level that
+    // can satisfy the request is tried first, and no candidate level at
+    // all means out of memory without touching the tree. With `pop`,
+    // levels with a free list take its first block instead of searching.
+    fn find(&mut self, lg2_size: uint, pop: bool) -> Option<(uint, uint)> {
+        loop {
+            let levels = (self.free_levels & ((2 << self.order) - 1)) >> lg2_size << lg2_size;
+            if levels == 0 {
+                return None;
+            }
+            let level = unsafe { cttz32(levels as i32) } as uint;
+            let found = if pop && self.listed(level) {
+                let head = self.free_heads[level];
+                if head == NIL { None } else { Some(self.index_at(head, level)) }
+            }
+            else if level == 0 {
+                match self.split_policy {
+                    LeftFirst => self.find_leaf(),
+                    RightFirst => self.find_at(0)
                 }
-                (UNUSED, false) => {
-                    // This large node is unused, split it!
-                    self.set(index, SPLIT);
-                    self.set(index*2 + 1, UNUSED);
-                    self.set(index*2 + 2, UNUSED);
+            }
+            else {
+                self.find_at(level)
+            };
+            match found {
+                Some(index) => return Some(self.take(index, level, lg2_size, self.split_policy)),
+                None => self.free_levels &= !(1 << level)
+            }
+        }
+    }
+
+    /// Allocate `size` units from a block that ends at or below offset
+    /// `limit`, for devices that can only reach the low part of the arena.
+    /// Returns `None` if no free block that low is large enough, without
+    /// calling the OOM handler.
+    pub fn alloc_below(&mut self, size: uint, limit: uint) -> Option<(uint, uint)> {
+        let lg2_size = lg2_ceil(if size == 0 { 1 } else { size });
+        if lg2_size > self.order {
+            return None;
+        }
+        let mut index = 0;
+        let mut level = self.order;
+
+        loop {
+            // Nodes are visited in address order, so once the leftmost
+            // block one would give ends above `limit`, so do all the rest.
+            if self.offset(index, level) + (1 << lg2_size) > limit {
+                return None;
+            }
+            match self.get(index) {
+                UNUSED if level >= lg2_size =>
+                    return Some(self.take(index, level, lg2_size, LeftFirst)),
+                SPLIT if level > lg2_size => {
                     index = index * 2 + 1; // left child
                     level -= 1;
+                    continue;
+                }
+                _ => {}
+            }
+            loop {
+                // Go either right or back up
+                if index & 1 == 1 {
+                    index += 1;
+                    break;
                 }
-                (SPLIT, false) => {
+                level += 1;
+                if index == 0 {
+                    return None;
+                }
+                index = (index + 1) / 2 - 1; // parent
+            }
+        }
+    }
+
+    // Index of the first live unused node at exactly `target`, searching
+    // in the order of `split_policy`
+    fn find_at(&self, target: uint) -> Option<uint> {
+        let mut index = 0;
+        let mut level = self.order;
+
+        loop {
+            match self.get(index) {
+                UNUSED if level == target => return Some(index),
+                SPLIT if level > target => {
                     // Traverse children
-                    index = index * 2 + 1; // left child
+                    index = first_child(self.split_policy, index);
                     level -= 1;
+                    continue;
                 }
-                _ => loop {
-                    // Go either right or back up
-                    if index & 1 == 1 {
-                        // right sibling
-                        index += 1;
+                _ => {}
+            }
+            loop {
+                // Go either to the other sibling or back up
+                match other_sibling(self.split_policy, index) {
+                    Some(sibling) => {
+                        index = sibling;
                         break;
                     }
+                    None => {}
+                }
+                level += 1;
+                if index == 0 {
+                    // back at the root after the whole traversal
+                    return None;
+                }
+                index = (index + 1) / 2 - 1; // parent
+            }
+        }
+    }
+
+    // `find_at(0)` for `LeftFirst`, scanning the leaf row a word at a time
+    // rather than descending from the root, which pays off for trees of
+    // single-unit blocks such as frames. A stale match lies in the block
+    // of a used or unused ancestor, so the scan resumes past that block.
+    fn find_leaf(&self) -> Option<uint> {
+        let first = (1 << self.order) - 1;
+        let end = (2 << self.order) - 1;
+        let mut from = first;
+        loop {
+            let leaf = match self.tree.find_first(UNUSED as u8, from, end) {
+                Some(leaf) => leaf,
+                None => return None
+            };
+            // The topmost ancestor that isn't SPLIT or FULL, if any
+            let mut index = leaf;
+            let mut stale = None;
+            while index > 0 {
+                index = (index + 1) / 2 - 1; // parent
+                match self.get(index) {
+                    SPLIT | FULL => {}
+                    _ => stale = Some(index)
+                }
+            }
+            match stale {
+                None => return Some(leaf),
+                Some(index) => {
+                    let level = self.level(index);
+                    from = first + self.offset(index, level) + (1 << level);
+                }
+            }
+        }
+    }
 
-                    // go up by one level
-                    level += 1;
+    // Split the live unused node `index` at `level` down to `lg2_size` and
+    // use its descendant there on the side `policy` picks.
+    fn take(&mut self, mut index: uint, mut level: uint, lg2_size: uint,
+            policy: SplitPolicy) -> (uint, uint) {
+        let start = self.offset(index, level);
+        self.unlink_free(start, level);
+        while level > lg2_size {
+            self.set(index, SPLIT);
+            self.set(index * 2 + 1, UNUSED);
+            self.set(index * 2 + 2, UNUSED);
+            index = first_child(policy, index);
+            level -= 1;
+            let other = buddy_of(index);
+            self.push_free(self.offset(other, level), level);
+        }
+        self.set(index, USED);
+        self.fill(index);
+        self.charge(1 << lg2_size);
+        self.alloc_count += 1;
+        let offset = self.offset(index, level);
+        self.touch(offset + (1 << lg2_size));
+        if cfg!(not(ndebug)) {
+            // Every block starts on a multiple of its size
+            assert(offset & ((1 << lg2_size) - 1) == 0);
+        }
+        (offset, 1 << lg2_size)
+    }
 
-                    if index == 0 {
-                        // out of memory -- back at tree's root after traversal
-                        return (0, 0);
-                    }
+    /// Declare the memory of every free block to be zero, such as frames
+    /// fresh from firmware, so that `Alloc::zero_alloc` doesn't clear
+    /// space above the highest block in use until it is handed out. A
+    /// block that has been handed out since is zeroed as usual.
+    pub fn assume_zeroed(&mut self) {
+        let mut end = 0;
+        for (offset, size, state) in self.extents() {
+            match state {
+                USED => end = offset + size,
+                _ => {}
+            }
+        }
+        self.fresh_from = end;
+    }
+
+    // Note that the units below `end` have been handed out.
+    #[inline]
+    fn touch(&mut self, end: uint) {
+        if end > self.fresh_from {
+            self.fresh_from = end;
+        }
+    }
+
+    fn charge(&mut self, size: uint) {
+        self.used += size;
+        if self.used > self.peak_used {
+            self.peak_used = self.used;
+        }
+    }
 
+    // Mark ancestors of a newly used node FULL while both of their
+    // children are exhausted.
+    fn fill(&mut self, mut index: uint) {
+        while index > 0 {
+            let buddy = buddy_of(index);
+            match self.get(buddy) {
+                USED | FULL => {
                     index = (index + 1) / 2 - 1; // parent
+                    self.set(index, FULL);
                 }
+                _ => break
             }
         }
     }
 
-    fn free(&mut self, offset: uint) {
-        let mut length = 1 << self.order;
+    // Locate the allocated block containing `offset` as (offset, size).
+    fn block_at(&self, offset: uint) -> Option<(uint, uint)> {
+        match self.node_at(offset) {
+            Some((_, level, left)) => Some((left, 1 << level)),
+            None => None
+        }
+    }
+
+    // Locate the used node containing `offset` as (index, level, offset).
+    fn node_at(&self, offset: uint) -> Option<(uint, uint, uint)> {
+        match self.cover(offset) {
+            (index, level, left, USED) => Some((index, level, left)),
+            _ => None
+        }
+    }
+
+    // The unused or used node containing `offset` as (index, level,
+    // offset, state).
+    fn cover(&self, offset: uint) -> (uint, uint, uint, Node) {
+        let mut level = self.order;
         let mut left = 0;
         let mut index = 0;
 
         loop {
-            match self.get(index) {
-                UNUSED => return,
-                USED => loop {
-                    if index == 0 {
-                        self.set(0, UNUSED);
-                        return;
-                    }
-
-                    let buddy = index - 1 + (index & 1) * 2;
-                    match self.get(buddy) {
-                        UNUSED => {}
-                        _ => {
-                            self.set(index, UNUSED);
-                            loop {
-                                let parent = (index + 1) / 2 - 1; // parent
-                                match self.get(parent) {
-                                    FULL if index > 0 => {
-                                        self.set(parent, SPLIT);
-                                    }
-                                    _ => return
-                                }
-                                index = parent;
-                            }
-                        }
-                    }
-                    index = (index + 1) / 2 - 1; // parent
-                },
+            let state = self.get(index);
+            match state {
+                UNUSED | USED => return (index, level, left, state),
                 _ => {
-                    length /= 2;
-                    if offset < left + length {
+                    level -= 1;
+                    if offset < left + (1 << level) {
                         index = index * 2 + 1; // left child
                     }
                     else {
-                        left += length;
+                        left += 1 << level;
                         index = index * 2 + 2; // right child
                     }
                 }
@@ -168,38 +943,3869 @@ impl BuddyAlloc {
         }
     }
 
-    fn get(&self, i: uint) -> Node {
-        unsafe {
-            transmute(self.tree.get(i))
+    // Extend the block starting at `offset` to `1 << lg2_size` units
+    // without moving it. This only works while the block is a left child
+    // whose right buddy is unused, at every level up to the target.
+    fn grow(&mut self, offset: uint, lg2_size: uint) -> bool {
+        let (mut index, mut level) = match self.node_at(offset) {
+            Some((index, level, left)) if left == offset => (index, level),
+            _ => return false
+        };
+        let old_level = level;
+        if lg2_size > self.order {
+            return false;
         }
-    }
 
-    fn set(&self, i: uint, x: Node) {
-        self.tree.set(i, x as u8);
-    }
-}
+        while level < lg2_size {
+            if index & 1 == 0 {
+                return false;
+            }
+            match self.get(index + 1) {
+                UNUSED => {}
+                _ => return false
+            }
+            index = (index + 1) / 2 - 1; // parent
+            level += 1;
+        }
 
-impl Allocator for Alloc {
-    fn alloc(&mut self, size: uint) -> (*mut u8, uint) {
-        let (offset, size) = self.parent.alloc(size);
-        unsafe {
-            return (
-                mut_offset(self.base, (offset << self.el_size) as int),
-                size << self.el_size
-            )
+        // The block stays at `offset`, so the buddies it absorbs follow it.
+        let mut absorbed = old_level;
+        while absorbed < level {
+            self.unlink_free(offset + (1 << absorbed), absorbed);
+            absorbed += 1;
         }
+
+        // The coalesced node is now the block; the nodes below it are stale.
+        self.set(index, USED);
+        self.fill(index);
+        self.charge((1 << level) - (1 << old_level));
+        self.touch(offset + (1 << level));
+        true
     }
 
-    fn free(&mut self, ptr: *mut u8) {
-        let length = 1 << self.parent.order << self.el_size;
+    /// Free every block at once by clearing the tree, except for pinned
+    /// blocks and reserved ranges, which are put back.
+    pub fn reset(&mut self) {
+        let mut pinned_sizes = [0u, ..MAX_PINNED];
+        let mut i = 0;
+        while i < self.pinned_count {
+            pinned_sizes[i] = match self.block_at(self.pinned[i]) {
+                Some((_, size)) => size,
+                None => 0
+            };
+            i += 1;
+        }
 
-        unsafe {
-            if ptr < self.base || ptr >= mut_offset(self.base, length) {
-                return;
-            }
+        // The lists start over from the one free block the tree is left as.
+        let links = self.links;
+        self.stop_lists();
+        self.tree.clear(Bitv::nodes_for_order(self.order));
+        self.free_levels = !0;
+        self.used = 0;
+        self.alloc_count = 0;
+        if links as uint != 0 {
+            self.start_lists(links, self.link_shift);
         }
 
-        let offset = (ptr as uint - self.base as uint) >> self.el_size;
-        self.parent.free(offset);
+        // The tree is empty, so each range is taken whole and recorded again.
+        let reserved = self.reserved;
+        let count = self.reserved_count;
+        self.reserved_count = 0;
+        i = 0;
+        while i < count {
+            let (start, end) = reserved[i];
+            self.reserve(start, end - start);
+            i += 1;
+        }
+        i = 0;
+        while i < self.pinned_count {
+            let offset = self.pinned[i];
+            self.alloc_at(offset, pinned_sizes[i]);
+            i += 1;
+        }
+    }
+
+    /// Allocate like `alloc_aligned`, but keep the block through `reset`.
+    /// It is unpinned when freed. Fails once `MAX_PINNED` blocks are
+    /// pinned.
+    pub fn alloc_pinned(&mut self, size: uint, align: uint) -> Option<(uint, uint)> {
+        if self.pinned_count == MAX_PINNED {
+            return None;
+        }
+        match self.alloc_aligned(size, align) {
+            Some((offset, size)) => {
+                self.pinned[self.pinned_count] = offset;
+                self.pinned_count += 1;
+                Some((offset, size))
+            }
+            None => None
+        }
+    }
+
+    /// Allocate the block of `size` units rounded up to a power of two at
+    /// `offset`, which must be a multiple of the rounded size. Returns
+    /// `None` if any of that block is in use.
+    pub fn alloc_at(&mut self, offset: uint, size: uint) -> Option<(uint, uint)> {
+        let lg2 = lg2_ceil(if size == 0 { 1 } else { size });
+        if lg2 > self.order || offset & ((1 << lg2) - 1) != 0 || offset >= 1 << self.order {
+            return None;
+        }
+
+        let mut index = 0;
+        let mut level = self.order;
+        // Whether `index` is live, so on a free list if it is unused. Below
+        // the first unused node on the way, nodes are split fresh.
+        let mut live = true;
+        while level > lg2 {
+            match self.get(index) {
+                UNUSED => {
+                    if live {
+                        self.unlink_free(self.offset(index, level), level);
+                        live = false;
+                    }
+                    self.set(index, SPLIT);
+                    self.set(index * 2 + 1, UNUSED);
+                    self.set(index * 2 + 2, UNUSED);
+                }
+                SPLIT => {}
+                _ => return None
+            }
+            level -= 1;
+            index = if offset & (1 << level) == 0 { index * 2 + 1 } else { index * 2 + 2 };
+            if !live {
+                // The half off the way stays free
+                self.push_free((offset >> level << level) ^ (1 << level), level);
+            }
+        }
+
+        match self.get(index) {
+            UNUSED => {}
+            _ => return None
+        }
+        if live {
+            self.unlink_free(offset, lg2);
+        }
+        self.set(index, USED);
+        self.fill(index);
+        self.charge(1 << lg2);
+        self.alloc_count += 1;
+        self.touch(offset + (1 << lg2));
+        Some((offset, 1 << lg2))
+    }
+
+    // Cut the block starting at `offset` down to `1 << lg2_size` units in
+    // place, returning its upper parts to the tree. Returns false if there
+    // is no such block or it is already that small.
+    fn shrink(&mut self, offset: uint, lg2_size: uint) -> bool {
+        let (mut index, mut level) = match self.node_at(offset) {
+            Some((index, level, left)) if left == offset => (index, level),
+            _ => return false
+        };
+        if level <= lg2_size || self.is_reserved(offset, 1 << level) {
+            return false;
+        }
+        let old_level = level;
+
+        // Nothing above is full any more.
+        let mut parent = index;
+        while parent > 0 {
+            parent = (parent + 1) / 2 - 1;
+            match self.get(parent) {
+                FULL => self.set(parent, SPLIT),
+                _ => break
+            }
+        }
+
+        // Keep the left child at each level and free its buddy.
+        while level > lg2_size {
+            self.set(index, SPLIT);
+            self.set(index * 2 + 2, UNUSED);
+            index = index * 2 + 1;
+            level -= 1;
+            self.push_free(offset + (1 << level), level);
+        }
+        self.set(index, USED);
+        self.used -= (1 << old_level) - (1 << level);
+        true
+    }
+
+    fn free(&mut self, offset: uint) {
+        match self.free_checked(offset) {
+            // Freeing free space is a caller bug; catch it in debug builds.
+            Err(_) if cfg!(not(ndebug)) => abort(),
+            _ => {}
+        }
+    }
+
+    /// Free the block starting at `offset`, failing with `DoubleFree` if
+    /// `offset` lies in free space and `InteriorPointer` if it is inside a
+    /// block but not at its start.
+    pub fn free_checked(&mut self, offset: uint) -> Result<(), FreeError> {
+        let mut length = 1 << self.order;
+        let mut left = 0;
+        let mut index = 0;
+
+        loop {
+            match self.get(index) {
+                UNUSED => return Err(DoubleFree),
+                USED if offset != left => return Err(InteriorPointer),
+                USED if self.is_reserved(left, length) => return Err(Reserved),
+                USED => {
+                    self.release(index);
+                    return Ok(());
+                }
+                _ => {
+                    length /= 2;
+                    if offset < left + length {
+                        index = index * 2 + 1; // left child
+                    }
+                    else {
+                        left += length;
+                        index = index * 2 + 2; // right child
+                    }
+                }
+            }
+        }
+    }
+
+    /// Free the block of `size` units starting at `offset`, where `size`
+    /// is the size `alloc` returned for it. This finds the block's node
+    /// directly instead of descending from the root.
+    pub fn free_sized(&mut self, offset: uint, size: uint) {
+        let level = lg2_ceil(size);
+        let index = (1 << (self.order - level)) - 1 + (offset >> level);
+        if cfg!(not(ndebug)) {
+            match self.node_at(offset) {
+                Some((i, _, left)) if i == index && left == offset => {}
+                _ => abort()
+            }
+        }
+        if self.is_reserved(offset, 1 << level) {
+            return;
+        }
+        self.release(index);
+    }
+
+    /// Take the `size` units from `offset` out of use for good, such as
+    /// memory-mapped I/O or the kernel image. Free blocks overlapping the
+    /// range are split down to the leaves needed to cover it exactly and
+    /// marked used; blocks already allocated in it are left alone and can
+    /// still be freed, after which their space is allocatable again. Only
+    /// the space actually taken is recorded as reserved: it counts as used
+    /// and can't be freed. Recorded ranges that touch are merged; past
+    /// `MAX_RESERVED` separate ranges, the blocks are still taken but not
+    /// protected from `free`, and this returns false.
+    pub fn reserve(&mut self, offset: uint, size: uint) -> bool {
+        let end = offset + size;
+        let total = 1 << self.order;
+        let end = if end > total || end < offset { total } else { end };
+        if offset >= end {
+            return true;
+        }
+        let mut recorded = true;
+        self.reserve_under(0, self.order, 0, offset, end, true, &mut recorded);
+        recorded
+    }
+
+    // Reserve `[lo, hi)` within node `index` at `level`, which starts at
+    // `left`, and return the node's new state. `live` is false for nodes
+    // split fresh here, which aren't on a free list yet; their unused
+    // halves are only listed once the range is taken, since the range may
+    // not be backed by memory the links can go in. Clears `recorded` if a
+    // block taken couldn't be recorded.
+    fn reserve_under(&mut self, index: uint, level: uint, left: uint, lo: uint, hi: uint,
+                     live: bool, recorded: &mut bool) -> Node {
+        let right = left + (1 << level);
+        let state = self.get(index);
+        if hi <= left || lo >= right {
+            return state;
+        }
+        match state {
+            USED | FULL => return state,
+            UNUSED if lo <= left && hi >= right => {
+                if live {
+                    self.unlink_free(left, level);
+                }
+                self.set(index, USED);
+                self.charge(1 << level);
+                if !self.record_reserved(left, right) {
+                    *recorded = false;
+                }
+                return USED;
+            }
+            UNUSED => {
+                if live {
+                    self.unlink_free(left, level);
+                }
+                self.set(index, SPLIT);
+                self.set(index * 2 + 1, UNUSED);
+                self.set(index * 2 + 2, UNUSED);
+            }
+            SPLIT => {}
+        }
+
+        let split = match state { UNUSED => true, _ => false };
+        let half = 1 << (level - 1);
+        let l = self.reserve_under(index * 2 + 1, level - 1, left, lo, hi, !split, recorded);
+        let r = self.reserve_under(index * 2 + 2, level - 1, left + half, lo, hi, !split,
+                                   recorded);
+        if split {
+            match l { UNUSED => self.push_free(left, level - 1), _ => {} }
+            match r { UNUSED => self.push_free(left + half, level - 1), _ => {} }
+        }
+        let full = match (l, r) {
+            (USED, USED) | (USED, FULL) | (FULL, USED) | (FULL, FULL) => true,
+            _ => false
+        };
+        let state = if full { FULL } else { SPLIT };
+        self.set(index, state);
+        state
+    }
+
+    // Add `[start, end)` to the reserved ranges, growing a range it touches
+    // rather than taking a new slot. Returns false if no slot is left.
+    fn record_reserved(&mut self, start: uint, end: uint) -> bool {
+        let mut i = 0;
+        while i < self.reserved_count {
+            let (lo, hi) = self.reserved[i];
+            if start <= hi && end >= lo {
+                self.reserved[i] = (if start < lo { start } else { lo },
+                                    if end > hi { end } else { hi });
+                return true;
+            }
+            i += 1;
+        }
+        if self.reserved_count == MAX_RESERVED {
+            return false;
+        }
+        self.reserved[self.reserved_count] = (start, end);
+        self.reserved_count += 1;
+        true
+    }
+
+    // Drop the pin on the block at `offset`, if any.
+    fn unpin(&mut self, offset: uint) {
+        let mut i = 0;
+        while i < self.pinned_count {
+            if self.pinned[i] == offset {
+                self.pinned_count -= 1;
+                self.pinned[i] = self.pinned[self.pinned_count];
+                return;
+            }
+            i += 1;
+        }
+    }
+
+    // Whether the block at `offset` is pinned
+    fn is_pinned(&self, offset: uint) -> bool {
+        let mut i = 0;
+        while i < self.pinned_count {
+            if self.pinned[i] == offset {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    // Whether the `size` units from `offset` overlap a reserved range
+    fn is_reserved(&self, offset: uint, size: uint) -> bool {
+        let mut i = 0;
+        while i < self.reserved_count {
+            let (start, end) = self.reserved[i];
+            if offset < end && offset + size > start {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    // Return the used node `index` to the tree, coalescing it with free
+    // buddies and clearing FULL marks above it.
+    fn release(&mut self, mut index: uint) {
+        let mut level = self.level(index);
+        self.used -= 1 << level;
+        self.alloc_count -= 1;
+        if self.pinned_count != 0 {
+            self.unpin(self.offset(index, level));
+        }
+        loop {
+            if index == 0 {
+                self.set(0, UNUSED);
+                self.push_free(0, level);
+                return;
+            }
+
+            let buddy = buddy_of(index);
+            match self.get(buddy) {
+                UNUSED => self.unlink_free(self.offset(buddy, level), level),
+                _ => {
+                    self.set(index, UNUSED);
+                    self.push_free(self.offset(index, level), level);
+                    while index > 0 {
+                        let parent = (index + 1) / 2 - 1;
+                        match self.get(parent) {
+                            FULL => self.set(parent, SPLIT),
+                            _ => return
+                        }
+                        index = parent;
+                    }
+                    return;
+                }
+            }
+            index = (index + 1) / 2 - 1; // parent
+            level += 1;
+        }
+    }
+
+    /// Walk the tree checking that every node's state agrees with its
+    /// children's, that the used count matches the used blocks and that
+    /// the free lists hold exactly the unused blocks at their levels,
+    /// describing the first problem found.
+    pub fn check_invariants(&self) -> Result<(), &'static str> {
+        match self.check_under(0, self.order) {
+            Ok(used) if used != self.used => Err("used count doesn't match used blocks"),
+            Ok(_) => self.check_lists(),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn check_lists(&self) -> Result<(), &'static str> {
+        let counts = self.free_counts();
+        let mut level = 0;
+        while level <= self.order {
+            if !self.listed(level) {
+                level += 1;
+                continue;
+            }
+            let mut count = 0;
+            let mut prev = NIL;
+            let mut offset = self.free_heads[level];
+            while offset != NIL {
+                // More entries than unused blocks means one is listed twice.
+                if count == counts[level] {
+                    return Err("free list longer than the unused blocks at its level");
+                }
+                if offset >= 1 << self.order || offset & ((1 << level) - 1) != 0 {
+                    return Err("free list entry off its level's block boundaries");
+                }
+                let index = self.index_at(offset, level);
+                match self.get(index) {
+                    UNUSED if self.is_live(index) => {}
+                    _ => return Err("free list entry isn't an unused block")
+                }
+                let link = self.link(offset);
+                if unsafe { (*link).prev } != prev {
+                    return Err("free list back link doesn't match");
+                }
+                prev = offset;
+                offset = unsafe { (*link).next };
+                count += 1;
+            }
+            if count != counts[level] {
+                return Err("unused block missing from its free list");
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+
+    // Check the subtree at `index` on `level`, returning the units used
+    // in it.
+    fn check_under(&self, index: uint, level: uint) -> Result<uint, &'static str> {
+        let state = self.get(index);
+        match state {
+            UNUSED if self.free_levels & (1 << level) == 0 =>
+                return Err("unused node at a level missing from the summary"),
+            UNUSED => return Ok(0),
+            USED => return Ok(1 << level),
+            _ if level == 0 => return Err("split or full leaf"),
+            _ => {}
+        }
+
+        let l = self.get(index * 2 + 1);
+        let r = self.get(index * 2 + 2);
+        let taken = |n: Node| match n { USED | FULL => true, _ => false };
+        match state {
+            SPLIT if l as u8 == UNUSED as u8 && r as u8 == UNUSED as u8 =>
+                return Err("split node with both children unused"),
+            SPLIT if taken(l) && taken(r) =>
+                return Err("split node with both children taken, should be full"),
+            FULL if !taken(l) || !taken(r) =>
+                return Err("full node with a child that isn't taken"),
+            _ => {}
+        }
+
+        let left = match self.check_under(index * 2 + 1, level - 1) {
+            Ok(used) => used,
+            Err(e) => return Err(e)
+        };
+        match self.check_under(index * 2 + 2, level - 1) {
+            Ok(used) => Ok(left + used),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Print the state of each level's nodes to the serial console: U for
+    /// unused, S for split, F for full and X for used. Nodes under unused
+    /// and used blocks are stale and omitted, so free subtrees show as a
+    /// single U.
+    #[cfg(not(ndebug))]
+    pub fn dump_tree(&self) {
+        self.write_tree(&mut Serial);
+    }
+
+    #[cfg(not(ndebug))]
+    fn write_tree<W: Writer>(&self, w: &mut W) {
+        let mut level = self.order;
+        loop {
+            let first = (1 << (self.order - level)) - 1;
+            let mut index = first;
+            // whether the next level has any live nodes
+            let mut split = false;
+
+            w.putuint(level, 10);
+            w.puts(":");
+            while index <= first * 2 {
+                if self.is_live(index) {
+                    w.putc(' ' as u8);
+                    w.putc(match self.get(index) {
+                        UNUSED => 'U',
+                        USED => 'X',
+                        SPLIT => { split = true; 'S' }
+                        FULL => { split = true; 'F' }
+                    } as u8);
+                }
+                index += 1;
+            }
+            w.puts("\n");
+
+            if !split || level == 0 {
+                break;
+            }
+            level -= 1;
+        }
+    }
+
+    pub fn extents<'a>(&'a self) -> Extents<'a> {
+        Extents {
+            alloc: self,
+            index: 0,
+            level: self.order,
+            offset: 0,
+            done: false,
+            pending: None
+        }
+    }
+
+    // The first used block at or after `from` as (offset, size) in units
+    fn next_used(&self, from: uint) -> Option<(uint, uint)> {
+        let mut extents = self.extents();
+        loop {
+            match extents.block() {
+                Some((offset, size, USED)) if offset >= from => return Some((offset, size)),
+                Some(_) => {}
+                None => return None
+            }
+        }
+    }
+
+    // A node is stale unless all of its ancestors are SPLIT or FULL.
+    fn is_live(&self, mut index: uint) -> bool {
+        while index > 0 {
+            index = (index + 1) / 2 - 1; // parent
+            match self.get(index) {
+                SPLIT | FULL => {}
+                _ => return false
+            }
+        }
+        true
+    }
+
+    // Height of node `index` above the leaves
+    #[inline]
+    fn level(&self, index: uint) -> uint {
+        self.order - (31 - unsafe { ctlz32((index + 1) as i32) } as uint)
+    }
+
+    fn get(&self, i: uint) -> Node {
+        unsafe {
+            transmute(self.tree.get(i))
+        }
+    }
+
+    fn set(&mut self, i: uint, x: Node) {
+        match x {
+            UNUSED => self.free_levels |= 1 << self.level(i),
+            _ => {}
+        }
+        self.tree.set(i, x as u8);
+    }
+}
+
+/// Write to `added` the blocks of snapshot `after` that aren't in the
+/// earlier snapshot `before`, such as leaks between the two. Both must be
+/// whole snapshots, in address order. Returns the number of added blocks,
+/// which is more than `added.len()` if only the first ones fit.
+pub fn snapshot_diff(before: &[*mut u8], after: &[*mut u8], added: &mut [*mut u8]) -> uint {
+    let mut count = 0;
+    let mut i = 0;
+    for &ptr in slice::iter(after) {
+        while i < before.len() && before[i] < ptr {
+            i += 1;
+        }
+        if i == before.len() || before[i] != ptr {
+            if count < added.len() {
+                added[count] = ptr;
+            }
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Build a byte-granular heap in `[start, end)`, keeping its tree at the
+/// front of the region. The order is the largest that fits next to its
+/// tree, so up to half of a region that isn't a power of two is left over
+/// at the end. The heap base is aligned to a page, or to the heap size for
+/// heaps smaller than a page.
+pub unsafe fn heap_init(start: *mut u8, end: *mut u8) -> Alloc {
+    let word = size_of::<u32>();
+    let storage = (start as uint + word - 1) & !(word - 1);
+    let end = end as uint;
+
+    let mut order = MAX_LEVELS;
+    let mut base = 0;
+    loop {
+        assert(order > 0);
+        order -= 1;
+        let size = 1 << order;
+        let align = if size < PAGE_SIZE { size } else { PAGE_SIZE };
+        base = (storage + bitv_bytes_for_order(order) + align - 1) & !(align - 1);
+        if base >= storage && base < end && end - base >= size {
+            break;
+        }
+    }
+
+    let tree = Bitv::from_storage(storage as *mut u32, order);
+    Alloc::new(base as *mut u8, order, 0, tree, base - storage)
+}
+
+// Page helpers hand out absolute page-aligned addresses, padding into the
+// block when `base` isn't page aligned. Keep `base` page aligned to avoid
+// wasting up to a page per allocation.
+impl Alloc {
+    /// An arena at `base` whose smallest block, the tree's leaf, is
+    /// `1 << min_block_shift` bytes, with `1 << order` leaves tracked in
+    /// `storage` of `storage_bytes` bytes. Sizes passed in are counted in
+    /// leaves; a larger leaf keeps the tree small when every allocation is
+    /// a multiple of it anyway, such as pages.
+    ///
+    /// `base` needn't be aligned, but requests aligned beyond it are
+    /// padded, and only `MAX_PADDED` of those can be out at once. An arena
+    /// that hands out pages with `alloc_pages` should be page-aligned.
+    pub fn new(base: *mut u8, order: uint, min_block_shift: uint, storage: Bitv,
+               storage_bytes: uint) -> Alloc {
+        Alloc {
+            parent: BuddyAlloc::new(order, storage, storage_bytes, false),
+            base: base,
+            el_size: min_block_shift,
+            canaries: 0 as *mut uint,
+            dirty: 0 as *mut u32,
+            oom_handler: None,
+            padded: [(0, 0), ..MAX_PADDED],
+            padded_count: 0
+        }
+    }
+
+    /// A byte-granular arena at `start` of the largest power of two that
+    /// fits before `end`. Whatever lies past that power of two is left
+    /// unused, up to half the range, so prefer power-of-two ranges.
+    pub fn from_range(start: *mut u8, end: *mut u8, storage: Bitv,
+                      storage_bytes: uint) -> Alloc {
+        assert(end > start);
+        let len = end as uint - start as uint;
+        let order = 31 - unsafe { ctlz32(len as i32) } as uint;
+        let order = if order >= MAX_LEVELS { MAX_LEVELS - 1 } else { order };
+        Alloc::new(start, order, 0, storage, storage_bytes)
+    }
+
+    /// An arena of `1 << size_shift` bytes at `base` with the smallest
+    /// leaves whose tree fits in `storage_bytes`. Returns the arena and its
+    /// leaf size in bytes, the real minimum allocation; sizes passed in are
+    /// counted in leaves as with `new`.
+    pub fn new_scaled(base: *mut u8, size_shift: uint, storage: Bitv,
+                      storage_bytes: uint) -> (Alloc, uint) {
+        let mut shift = 0;
+        while shift < size_shift && (size_shift - shift >= MAX_LEVELS ||
+                bitv_bytes_for_order(size_shift - shift) > storage_bytes) {
+            shift += 1;
+        }
+        (Alloc::new(base, size_shift - shift, shift, storage, storage_bytes), 1 << shift)
+    }
+
+    /// Bytes in the smallest block
+    pub fn min_block_size(&self) -> uint {
+        1 << self.el_size
+    }
+
+    /// Order of the tree: the largest block is `min_block_size() << order`
+    /// bytes.
+    pub fn order(&self) -> uint {
+        self.parent.order
+    }
+
+    /// Allocate `size` units aligned to `align` bytes, or `None` when out
+    /// of memory. A zero-sized request takes no block and returns
+    /// `zero_size_ptr(align)`, so `align` must be at most 1 MiB. If `base`
+    /// is less aligned than `align`, at most `MAX_PADDED` such allocations
+    /// can be out at once.
+    pub unsafe fn alloc_opt(&mut self, size: uint, align: uint) -> Option<(*mut u8, uint)> {
+        assert(align != 0 && align & (align - 1) == 0);
+        if size == 0 {
+            return Some((zero_size_ptr(align), 0));
+        }
+        // Blocks are only aligned relative to `base`. If `base` itself is
+        // not aligned, skip `pad` bytes into a block large enough to hold
+        // them. The whole block stays reserved, and the pad is recorded so
+        // that `free` can tell the pointer from one into the block. Fails
+        // once `MAX_PADDED` such blocks are out.
+        let pad = self.align_pad(align);
+        if pad != 0 && self.padded_count == MAX_PADDED {
+            return None;
+        }
+        let units = size + self.units_for(pad);
+        let align_units = align >> self.el_size;
+        let align_units = if align_units > 1 { align_units } else { 1 };
+
+        match self.alloc_or_reclaim(units, align_units) {
+            None => None,
+            Some((offset, units)) => {
+                let ptr = (self.ptr_at(offset) as uint + pad) as *mut u8;
+                let bytes = (units << self.el_size) - pad;
+                if cfg!(not(ndebug)) {
+                    assert(ptr as uint & (align - 1) == 0);
+                }
+                if pad != 0 {
+                    self.padded[self.padded_count] = (offset, pad);
+                    self.padded_count += 1;
+                }
+                self.place_canary(offset, ptr as uint + (size << self.el_size),
+                                  ptr as uint + bytes);
+                self.notify_alloc(ptr, bytes);
+                Some((ptr, bytes))
+            }
+        }
+    }
+
+    /// Install a handler called with the allocator and the requested size
+    /// in units when an allocation fails. If it returns `true` (e.g. after
+    /// freeing cached blocks into the allocator it is passed), the
+    /// allocation is retried once, after the tree is checked in debug
+    /// builds. This is the one place to respond to memory pressure, such
+    /// as by flushing caches or compacting a relocatable arena.
+    ///
+    /// The handler is called once the failed search is over, so it may
+    /// allocate, free and compact through its argument; allocations it
+    /// makes don't call it again. Behind a `LockedAllocator` the lock is
+    /// still held for the failed call, so it must use that argument rather
+    /// than going through the lock.
+    pub fn set_oom_handler(&mut self, handler: Option<fn(&mut Alloc, uint) -> bool>) {
+        self.oom_handler = handler;
+    }
+
+    // `BuddyAlloc::alloc_aligned`, giving the OOM handler a chance to make
+    // room when it fails
+    fn alloc_or_reclaim(&mut self, size: uint, align: uint) -> Option<(uint, uint)> {
+        match self.parent.alloc_aligned(size, align) {
+            None if level_for(size, align) <= self.parent.order => {}
+            r => return r
+        }
+        let handler = match self.oom_handler {
+            Some(handler) => handler,
+            None => return None
+        };
+        // Allocations the handler makes fail without calling it again.
+        self.oom_handler = None;
+        let reclaimed = handler(self, size);
+        self.oom_handler = Some(handler);
+        if !reclaimed {
+            return None;
+        }
+        if cfg!(not(ndebug)) {
+            match self.parent.check_invariants() {
+                Ok(()) => {}
+                Err(_) => abort()
+            }
+        }
+        self.parent.alloc_aligned(size, align)
+    }
+
+    /// `alloc_opt` with the block zeroed. Blocks lying in space not handed
+    /// out since `BuddyAlloc::assume_zeroed`, or with no unit marked in the
+    /// dirty map, are already zero and are not written.
+    pub unsafe fn zero_alloc_opt(&mut self, size: uint, align: uint) -> Option<(*mut u8, uint)> {
+        let fresh_from = self.parent.fresh_from;
+        match self.alloc_opt(size, align) {
+            Some((ptr, bytes)) if bytes != 0 => {
+                let (start, end) = match self.block_offset(ptr) {
+                    Some(offset) => match self.parent.block_at(offset) {
+                        Some((start, units)) => (start, start + units),
+                        None => (offset, offset)
+                    },
+                    None => (0, 0)
+                };
+                // Units `ptr` and the returned size cover, padding aside
+                let first = (ptr as uint - self.base as uint) >> self.el_size;
+                let clean = start >= fresh_from
+                    || (self.dirty as uint != 0 && !self.any_dirty(first, end));
+                if !clean {
+                    set_memory(ptr, 0, bytes);
+                    // Put back the canary the fill wiped
+                    self.place_canary(start, ptr as uint + (size << self.el_size),
+                                      ptr as uint + bytes);
+                    let zeroed = self.units_for(ptr as uint - self.base as uint);
+                    self.mark_dirty(zeroed, end, false);
+                }
+                Some((ptr, bytes))
+            }
+            r => r
+        }
+    }
+
+    /// Track in `map`, one bit per unit, which units may have been written
+    /// since `assume_zeroed`, so that `zero_alloc` only clears blocks
+    /// that need it. Units are marked as their blocks are freed, unless
+    /// `zero_on_free` cleared them, and unmarked once zeroed. `map` must
+    /// hold `((1 << order) + 31) / 32` words and starts with every unit
+    /// marked; pass null to stop tracking.
+    pub unsafe fn set_dirty_map(&mut self, map: *mut u32) {
+        if map as uint != 0 {
+            set_memory(map as *mut u8, 0xff, self.dirty_words() * size_of::<u32>());
+        }
+        self.dirty = map;
+    }
+
+    /// Keep a list of the free blocks at each level, linked through the
+    /// blocks themselves, so that allocating takes a block of the right
+    /// level off its list instead of searching the tree, which stays the
+    /// record used to coalesce. The links take the first two words of each
+    /// free block: levels with smaller blocks are still searched, and the
+    /// links are cleared as a block is handed out, so a poisoned free block
+    /// holds them in place of poison. `base` must be word aligned. Pass
+    /// false to clear the links and search again.
+    pub fn set_free_lists(&mut self, on: bool) {
+        if on {
+            assert(self.base as uint & (min_align_of::<FreeLink>() - 1) == 0);
+            self.parent.start_lists(self.base, self.el_size);
+        }
+        else {
+            self.parent.stop_lists();
+        }
+    }
+
+    /// Declare the memory of every free block zero. See
+    /// `BuddyAlloc::assume_zeroed`; this also unmarks every unit of the
+    /// dirty map, and blocks in use now are marked when they are freed.
+    pub fn assume_zeroed(&mut self) {
+        self.parent.assume_zeroed();
+        if self.dirty as uint != 0 {
+            unsafe {
+                set_memory(self.dirty as *mut u8, 0, self.dirty_words() * size_of::<u32>());
+            }
+        }
+    }
+
+    fn dirty_words(&self) -> uint {
+        ((1 << self.parent.order) + 31) / 32
+    }
+
+    // Mark or unmark the units in `[from, to)` in the dirty map.
+    fn mark_dirty(&mut self, from: uint, to: uint, dirty: bool) {
+        if self.dirty as uint == 0 {
+            return;
+        }
+        let mut i = from;
+        while i < to {
+            unsafe {
+                let word = mut_offset(self.dirty, (i / 32) as int);
+                if dirty {
+                    *word |= 1 << (i % 32);
+                }
+                else {
+                    *word &= !(1 << (i % 32));
+                }
+            }
+            i += 1;
+        }
+    }
+
+    // Whether any unit in `[from, to)` is marked in the dirty map
+    fn any_dirty(&self, from: uint, to: uint) -> bool {
+        let mut i = from;
+        while i < to {
+            if unsafe { *mut_offset(self.dirty, (i / 32) as int) } & (1 << (i % 32)) != 0 {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    // Bytes to skip into a block so that it is aligned to `align`
+    fn align_pad(&self, align: uint) -> uint {
+        (align - (self.base as uint & (align - 1))) & (align - 1)
+    }
+
+    // Bytes `alloc_opt` skipped into the block at `offset`
+    fn pad_of(&self, offset: uint) -> uint {
+        let mut i = 0;
+        while i < self.padded_count {
+            match self.padded[i] {
+                (start, pad) if start == offset => return pad,
+                _ => {}
+            }
+            i += 1;
+        }
+        0
+    }
+
+    // Forget the pad of the block at `offset`, if any.
+    fn unpad(&mut self, offset: uint) {
+        let mut i = 0;
+        while i < self.padded_count {
+            match self.padded[i] {
+                (start, _) if start == offset => {
+                    self.padded_count -= 1;
+                    self.padded[i] = self.padded[self.padded_count];
+                    return;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    // Units needed to hold `bytes`
+    fn units_for(&self, bytes: uint) -> uint {
+        (bytes >> self.el_size) + if bytes & ((1 << self.el_size) - 1) != 0 { 1 } else { 0 }
+    }
+
+    /// Allocate `size` bytes aligned to `align`, as described by a
+    /// `Layout`, returning null when out of memory. Like `alloc_opt`, a
+    /// zero-sized request returns `zero_size_ptr(align)`.
+    pub unsafe fn alloc_layout(&mut self, size: uint, align: uint) -> *mut u8 {
+        match self.alloc_opt(self.units_for(size), align) {
+            None => 0 as *mut u8,
+            Some((ptr, _)) => ptr
+        }
+    }
+
+    /// Free `ptr`, allocated by `alloc_layout` with the same `size` and
+    /// `align`. The block's size follows from them, so it is freed with
+    /// `free_sized` rather than found in the tree. That no longer holds
+    /// once the block is resized in place, which needs a plain `free`.
+    pub unsafe fn dealloc_layout(&mut self, ptr: *mut u8, size: uint, align: uint) {
+        if size == 0 || ptr as uint == 0 {
+            return;
+        }
+        // Same rounding as `alloc_opt` and `BuddyAlloc::alloc_aligned`
+        let pad = self.align_pad(align);
+        let mut level = lg2_ceil(self.units_for(size) + self.units_for(pad));
+        let align_units = align >> self.el_size;
+        if align_units > 1 && lg2_ceil(align_units) > level {
+            level = lg2_ceil(align_units);
+        }
+        self.free_sized(ptr, (1 << level << self.el_size) - pad);
+    }
+
+    /// Free every allocation except pinned ones. See `BuddyAlloc::reset`.
+    /// Each block freed is checked and zeroed or poisoned as `free` would,
+    /// so with either of those on this writes every allocated byte.
+    /// Canaries of the pinned blocks are no longer checked. The dirty map
+    /// is not cleared: the freed blocks still hold what was written to
+    /// them, so they are marked like any other freed block and
+    /// `zero_alloc` still clears them. Only `assume_zeroed` unmarks units
+    /// without writing them.
+    pub fn reset(&mut self) {
+        let mut from = 0;
+        loop {
+            let (offset, units) = match self.parent.next_used(from) {
+                Some(block) => block,
+                None => break
+            };
+            from = offset + units;
+            if !self.parent.is_pinned(offset) {
+                self.retire(offset);
+            }
+        }
+        self.parent.reset();
+        if self.canaries as uint != 0 {
+            unsafe {
+                set_memory(self.canaries as *mut u8, 0,
+                           (1 << self.parent.order) * size_of::<uint>());
+            }
+        }
+    }
+
+    /// Move allocations toward the start of the arena so that the space
+    /// they leave coalesces into large blocks. In address order, each
+    /// block is copied to the lowest free block of its size if there is
+    /// one below it, then `relocate` is called with the old and new start
+    /// of the whole block and its size in bytes, so that the owner can fix
+    /// up pointers into it before the old block is freed. Pinned and
+    /// reserved blocks stay put, and the allocation hooks aren't called.
+    /// Only use this on arenas whose every block can be relocated. Returns
+    /// the number of blocks moved.
+    pub fn compact(&mut self, relocate: fn(*mut u8, *mut u8, uint)) -> uint {
+        // The copy briefly holds both blocks
+        let peak = self.parent.peak_used;
+        let mut moved = 0;
+        let mut from = 0;
+        loop {
+            let (offset, units) = match self.parent.next_used(from) {
+                Some(block) => block,
+                None => break
+            };
+            from = offset + units;
+            if self.parent.is_reserved(offset, units) || self.parent.is_pinned(offset) {
+                continue;
+            }
+            // The lowest free block that fits below this one, whatever the
+            // split policy or free lists would pick
+            match self.parent.alloc_below(units, offset) {
+                Some((new, _)) => {
+                    let old_ptr = self.ptr_at(offset);
+                    let new_ptr = self.ptr_at(new);
+                    let bytes = units << self.el_size;
+                    unsafe {
+                        copy_memory(new_ptr, old_ptr as *u8, bytes);
+                    }
+                    relocate(old_ptr, new_ptr, bytes);
+
+                    // The canary moved with the contents
+                    let canary = if self.canaries as uint == 0 {
+                        0
+                    }
+                    else {
+                        unsafe { *mut_offset(self.canaries, offset as int) }
+                    };
+                    // So did the pad, which stays aligned in a block of the
+                    // same size
+                    let pad = self.pad_of(offset);
+                    self.retire(offset);
+                    self.parent.free(offset);
+                    if canary != 0 {
+                        unsafe {
+                            *mut_offset(self.canaries, new as int) =
+                                canary - old_ptr as uint + new_ptr as uint;
+                        }
+                    }
+                    if pad != 0 {
+                        self.padded[self.padded_count] = (new, pad);
+                        self.padded_count += 1;
+                    }
+                    moved += 1;
+                }
+                None => {}
+            }
+        }
+        self.parent.peak_used = peak;
+        moved
+    }
+
+    /// Allocate `size` units lying entirely below `ceiling`, for devices
+    /// that can only DMA to low addresses. See `BuddyAlloc::alloc_below`.
+    pub fn alloc_below(&mut self, size: uint, ceiling: *mut u8) -> Option<(*mut u8, uint)> {
+        if ceiling <= self.base {
+            return None;
+        }
+        let limit = (ceiling as uint - self.base as uint) >> self.el_size;
+        match self.parent.alloc_below(size, limit) {
+            Some((offset, units)) => {
+                let ptr = self.ptr_at(offset);
+                let bytes = units << self.el_size;
+                self.place_canary(offset, ptr as uint + (size << self.el_size),
+                                  ptr as uint + bytes);
+                self.notify_alloc(ptr, bytes);
+                Some((ptr, bytes))
+            }
+            None => None
+        }
+    }
+
+    /// Allocate `size` units aligned to `align` bytes that survive `reset`.
+    /// See `BuddyAlloc::alloc_pinned`.
+    pub unsafe fn alloc_pinned(&mut self, size: uint, align: uint) -> Option<(*mut u8, uint)> {
+        // Pin the block `alloc_opt` took, which may start before `ptr`.
+        match self.alloc_opt(size, align) {
+            Some((ptr, bytes)) => {
+                let block = match self.offset_of(ptr) {
+                    Some(offset) => self.parent.block_at(offset),
+                    None => None
+                };
+                match block {
+                    Some((offset, _)) if self.parent.pinned_count < MAX_PINNED => {
+                        self.parent.pinned[self.parent.pinned_count] = offset;
+                        self.parent.pinned_count += 1;
+                        Some((ptr, bytes))
+                    }
+                    _ => {
+                        self.free(ptr);
+                        None
+                    }
+                }
+            }
+            None => None
+        }
+    }
+
+    /// Allocate `size` units at exactly `addr`, which must lie on a unit
+    /// boundary aligned to the rounded size relative to `base`. Returns
+    /// `None` if that space isn't all free. See `BuddyAlloc::alloc_at`.
+    pub fn alloc_at(&mut self, addr: *mut u8, size: uint) -> Option<(*mut u8, uint)> {
+        let offset = match self.offset_of(addr) {
+            Some(offset) => offset,
+            None => return None
+        };
+        if offset << self.el_size != addr as uint - self.base as uint {
+            return None;
+        }
+        match self.parent.alloc_at(offset, size) {
+            Some((_, units)) => {
+                self.place_canary(offset, addr as uint + (size << self.el_size),
+                                  addr as uint + (units << self.el_size));
+                self.notify_alloc(addr, units << self.el_size);
+                Some((addr, units << self.el_size))
+            }
+            None => None
+        }
+    }
+
+    /// Give back the end of the block at `ptr` beyond `new_size` units, in
+    /// place, when a smaller block will do. The end given back is zeroed or
+    /// poisoned as a freed block would be. Returns the usable size in bytes
+    /// from `ptr` afterwards, or 0 if `ptr` isn't an allocated block.
+    pub fn shrink_to(&mut self, ptr: *mut u8, new_size: uint) -> uint {
+        let block = match self.offset_of(ptr) {
+            Some(offset) => self.parent.block_at(offset),
+            None => None
+        };
+        match block {
+            Some((offset, old_units)) => {
+                let end = ptr as uint - self.base as uint + (new_size << self.el_size);
+                let units = ((end + (1 << self.el_size) - 1) >> self.el_size) - offset;
+                let new_units = 1 << lg2_ceil(units);
+                if new_units < old_units && !self.parent.is_reserved(offset, old_units) {
+                    // The tail given back is freed memory like any other.
+                    // It is scrubbed before it goes back to the tree, where
+                    // free lists keep their links in it.
+                    self.check_canary(offset);
+                    self.scrub(offset + new_units, offset + old_units);
+                    self.parent.shrink(offset, lg2_ceil(units));
+                    let block_end = self.base as uint + ((offset + new_units) << self.el_size);
+                    self.place_canary(offset, ptr as uint + (new_size << self.el_size), block_end);
+                }
+                self.size_from(ptr)
+            }
+            None => 0
+        }
+    }
+
+    /// Shrink the block at `ptr`, whose first `old_size` units are in use,
+    /// to `new_size` units from `ptr`; the counterpart of `grow`. Like
+    /// `shrink_to` it never moves the block. Returns the usable size in
+    /// bytes from `ptr` afterwards, or 0, leaving the block alone, if it
+    /// doesn't hold `old_size` units from `ptr`.
+    pub fn shrink(&mut self, ptr: *mut u8, old_size: uint, new_size: uint) -> uint {
+        assert(new_size <= old_size);
+        if old_size << self.el_size > self.size_from(ptr) {
+            return 0;
+        }
+        self.shrink_to(ptr, new_size)
+    }
+
+    /// Make the block at `ptr`, whose first `old_size` units are in use,
+    /// hold `new_size` units from `ptr`. The block is extended in place by
+    /// taking its free buddies if it can be; otherwise the data moves to a
+    /// new block and the old one is freed. Returns the pointer and usable
+    /// size in bytes, or `None`, leaving `ptr` alone, when out of memory or
+    /// if `ptr` isn't an allocated block.
+    pub fn grow(&mut self, ptr: *mut u8, old_size: uint,
+                new_size: uint) -> Option<(*mut u8, uint)> {
+        let have = self.size_from(ptr);
+        if have == 0 {
+            return None;
+        }
+        if new_size << self.el_size <= have {
+            self.move_canary(ptr, new_size);
+            return Some((ptr, have));
+        }
+        if self.grow_in_place(ptr, new_size) {
+            return Some((ptr, self.size_from(ptr)));
+        }
+        match unsafe { self.alloc_opt(new_size, 1) } {
+            Some((new, size)) => {
+                let count = old_size << self.el_size;
+                unsafe {
+                    copy_memory(new, ptr as *u8, if count < have { count } else { have });
+                }
+                self.free(ptr);
+                Some((new, size))
+            }
+            None => None
+        }
+    }
+
+    /// Resize the block at `src` to `size` units like `realloc`, keeping
+    /// the result aligned to `align` bytes when it has to move. Plain
+    /// `realloc` takes the first block that fits, which only keeps the
+    /// alignment buddy blocks get from their size. Returns `None`, leaving
+    /// `src` allocated, when out of memory, and without allocating anything
+    /// if `src` isn't an allocated block.
+    pub unsafe fn realloc_align(&mut self, src: *mut u8, size: uint,
+                                align: uint) -> Option<(*mut u8, uint)> {
+        assert(align != 0 && align & (align - 1) == 0);
+        if src as uint == 0 || is_zero_size(src) {
+            return self.alloc_opt(size, align);
+        }
+        if size == 0 {
+            self.free(src);
+            return Some((0 as *mut u8, 0));
+        }
+
+        let old_size = self.size_from(src);
+        if old_size == 0 {
+            return None;
+        }
+        if src as uint & (align - 1) == 0 {
+            if size << self.el_size <= old_size {
+                self.move_canary(src, size);
+                return Some((src, old_size));
+            }
+            if self.grow_in_place(src, size) {
+                return Some((src, self.size_from(src)));
+            }
+        }
+        match self.alloc_opt(size, align) {
+            Some((ptr, sz)) => {
+                copy_memory(ptr, src as *u8, if old_size < sz { old_size } else { sz });
+                self.free(src);
+                Some((ptr, sz))
+            }
+            None => None
+        }
+    }
+
+    // Extend the block at `ptr` in place to hold `size` units from `ptr`.
+    fn grow_in_place(&mut self, ptr: *mut u8, size: uint) -> bool {
+        let block = match self.offset_of(ptr) {
+            Some(offset) => self.parent.block_at(offset),
+            None => None
+        };
+        match block {
+            Some((offset, _)) => {
+                // Units needed from the start of the block
+                let end = ptr as uint - self.base as uint + (size << self.el_size);
+                let units = ((end + (1 << self.el_size) - 1) >> self.el_size) - offset;
+                if self.parent.grow(offset, lg2_ceil(units)) {
+                    self.move_canary(ptr, size);
+                    return true;
+                }
+                false
+            }
+            None => false
+        }
+    }
+
+    /// Allocate `count` elements of `elem_size` units each, or `None` if
+    /// the total overflows or memory runs out.
+    pub fn alloc_array(&mut self, count: uint, elem_size: uint) -> Option<(*mut u8, uint)> {
+        match mul_with_overflow(count, elem_size) {
+            (_, true) => None,
+            (size, _) => unsafe { self.alloc_opt(size, 1) }
+        }
+    }
+
+    /// `alloc_array` with the block zeroed.
+    pub fn zero_array(&mut self, count: uint, elem_size: uint) -> Option<(*mut u8, uint)> {
+        match mul_with_overflow(count, elem_size) {
+            (_, true) => None,
+            (size, _) => unsafe { self.zero_alloc_opt(size, 1) }
+        }
+    }
+
+    /// Uninitialized storage for one `T`, sized and aligned for it, or
+    /// `None` when out of memory. Nothing is written to it.
+    pub fn alloc_one<T>(&mut self) -> Option<*mut T> {
+        let units = self.units_for(size_of::<T>());
+        match unsafe { self.alloc_opt(units, min_align_of::<T>()) } {
+            Some((ptr, _)) => Some(ptr as *mut T),
+            None => None
+        }
+    }
+
+    /// Uninitialized storage for at least `n` elements of `T`, aligned for
+    /// it, as the pointer and the number of elements that fit. `None` if
+    /// the size overflows or memory runs out.
+    pub fn alloc_slice<T>(&mut self, n: uint) -> Option<(*mut T, uint)> {
+        let bytes = match mul_with_overflow(n, size_of::<T>()) {
+            (_, true) => return None,
+            (bytes, _) => bytes
+        };
+        match unsafe { self.alloc_opt(self.units_for(bytes), min_align_of::<T>()) } {
+            Some((ptr, _)) if size_of::<T>() == 0 => Some((ptr as *mut T, n)),
+            Some((ptr, size)) => Some((ptr as *mut T, size / size_of::<T>())),
+            None => None
+        }
+    }
+
+    /// Free storage from `alloc_one`, without running any destructor.
+    pub fn free_one<T>(&mut self, ptr: *mut T) {
+        self.free(ptr as *mut u8);
+    }
+
+    /// Write `CANARY` just past the requested size of each allocation whose
+    /// block has room for it, and abort on any free that finds it changed.
+    /// Canary positions are kept in `table`, which must hold `1 << order`
+    /// words; pass null to turn canaries off. Enable this before the first
+    /// allocation, as blocks allocated earlier aren't checked.
+    pub unsafe fn set_canaries(&mut self, table: *mut uint) {
+        if table as uint != 0 {
+            set_memory(table as *mut u8, 0, (1 << self.parent.order) * size_of::<uint>());
+        }
+        self.canaries = table;
+    }
+
+    // Record a canary at address `at` for the block at `offset`, if it
+    // fits before `end`.
+    fn place_canary(&mut self, offset: uint, at: uint, end: uint) {
+        if self.canaries as uint == 0 {
+            return;
+        }
+        unsafe {
+            let slot = mut_offset(self.canaries, offset as int);
+            if at + size_of::<u32>() > end {
+                *slot = 0;
+                return;
+            }
+            copy_memory(at as *mut u8, &CANARY as *u32 as *u8, size_of::<u32>());
+            *slot = at;
+        }
+    }
+
+    // Abort if the block at `offset` has a canary that was overwritten.
+    fn check_canary(&mut self, offset: uint) {
+        if self.canaries as uint == 0 {
+            return;
+        }
+        unsafe {
+            let slot = mut_offset(self.canaries, offset as int);
+            if *slot == 0 {
+                return;
+            }
+            let mut word = 0u32;
+            copy_memory(&mut word as *mut u32 as *mut u8, *slot as *u8, size_of::<u32>());
+            *slot = 0;
+            if word != CANARY {
+                abort();
+            }
+        }
+    }
+
+    // Offset in units of `ptr` if it lies within the arena.
+    fn offset_of(&self, ptr: *mut u8) -> Option<uint> {
+        // Compare in units: the arena's length in bytes can overflow.
+        if ptr < self.base {
+            return None;
+        }
+        let offset = (ptr as uint - self.base as uint) >> self.el_size;
+        if offset >= 1 << self.parent.order {
+            return None;
+        }
+        Some(offset)
+    }
+
+    // Address of unit `offset`. Computed on unsigned addresses, since an
+    // offset past `int`'s range would turn a signed offset negative.
+    fn ptr_at(&self, offset: uint) -> *mut u8 {
+        let addr = self.base as uint + (offset << self.el_size);
+        if cfg!(not(ndebug)) {
+            assert(offset < 1 << self.parent.order && addr >= self.base as uint);
+        }
+        addr as *mut u8
+    }
+
+    // Address of the last byte of the arena
+    fn last_addr(&self) -> uint {
+        self.base as uint + (((1 << self.parent.order) - 1) << self.el_size)
+            + ((1 << self.el_size) - 1)
+    }
+
+    // Tree offset of the block `ptr` was handed out for. A pointer padded
+    // for alignment (see `alloc_opt`) lies exactly its recorded pad past
+    // the start of its block. Other pointers keep their own offset for the
+    // tree to validate.
+    fn block_offset(&self, ptr: *mut u8) -> Option<uint> {
+        let offset = match self.offset_of(ptr) {
+            Some(offset) => offset,
+            None => return None
+        };
+        match self.parent.block_at(offset) {
+            Some((start, _)) if ptr as uint == self.ptr_at(start) as uint + self.pad_of(start) => {
+                Some(start)
+            }
+            _ => Some(offset)
+        }
+    }
+
+    // Bytes of the allocated block containing `ptr` from `ptr` to the end.
+    fn size_from(&self, ptr: *mut u8) -> uint {
+        let block = match self.offset_of(ptr) {
+            Some(offset) => self.parent.block_at(offset),
+            None => None
+        };
+        match block {
+            Some((offset, size)) => {
+                let end = self.base as uint + ((offset + size) << self.el_size);
+                end - ptr as uint
+            }
+            None => 0
+        }
+    }
+
+    /// Free `ptr` like `free`, but report pointers outside the arena as
+    /// `NotOwned` instead of ignoring them. Null and zero-sized pointers
+    /// are still accepted.
+    pub fn free_checked(&mut self, ptr: *mut u8) -> Result<(), FreeError> {
+        if ptr as uint == 0 || is_zero_size(ptr) {
+            return Ok(());
+        }
+        match self.block_offset(ptr) {
+            Some(offset) => {
+                let bytes = self.logged_size(offset);
+                self.retire(offset);
+                match self.parent.free_checked(offset) {
+                    Ok(()) => {
+                        self.notify_free(ptr, bytes);
+                        Ok(())
+                    }
+                    e => e
+                }
+            }
+            None => Err(NotOwned)
+        }
+    }
+
+    /// Free the allocated block holding `ptr`, wherever in the block `ptr`
+    /// points, for callers that only keep a pointer into an allocation.
+    /// Use this only where that is expected: a stray pointer into a live
+    /// block frees it, and a pointer into free space can't be told from a
+    /// double free, both failing with `DoubleFree`.
+    pub fn free_interior(&mut self, ptr: *mut u8) -> Result<(), FreeError> {
+        let offset = match self.offset_of(ptr) {
+            Some(offset) => offset,
+            None => return Err(NotOwned)
+        };
+        let (start, units) = match self.parent.block_at(offset) {
+            Some(block) => block,
+            None => return Err(DoubleFree)
+        };
+        let block = self.ptr_at(start);
+        self.retire(start);
+        match self.parent.free_checked(start) {
+            Ok(()) => {
+                self.notify_free(block, units << self.el_size);
+                Ok(())
+            }
+            e => e
+        }
+    }
+
+    /// Free `ptr` given the size in bytes `alloc` returned for it, without
+    /// searching the tree for its block.
+    pub fn free_sized(&mut self, ptr: *mut u8, size: uint) {
+        let offset = match self.offset_of(ptr) {
+            Some(offset) => offset,
+            None => return
+        };
+        // The block ends exactly at `ptr + size`, even when `ptr` was
+        // padded for alignment.
+        let end = (ptr as uint - self.base as uint + size) >> self.el_size;
+        let mut level = lg2_ceil(end - offset);
+        while level < self.parent.order && (offset >> level << level) + (1 << level) != end {
+            level += 1;
+        }
+        let start = offset >> level << level;
+        if self.parent.is_reserved(start, 1 << level) {
+            return;
+        }
+        self.retire(start);
+        self.parent.free_sized(start, 1 << level);
+        self.notify_free(ptr, 1 << level << self.el_size);
+    }
+
+    // Size in bytes of the block at `offset` while logging, for
+    // `notify_free`; it has to be read before the block is freed.
+    fn logged_size(&self, offset: uint) -> uint {
+        if self.parent.event_capacity == 0 {
+            return 0;
+        }
+        match self.parent.block_at(offset) {
+            Some((_, units)) => units << self.el_size,
+            None => 0
+        }
+    }
+
+    fn notify_alloc(&mut self, ptr: *mut u8, bytes: uint) {
+        self.parent.log_event(Allocated, ptr, bytes);
+        match self.parent.on_alloc {
+            Some(hook) => hook(ptr, bytes),
+            None => {}
+        }
+    }
+
+    // `bytes` is the size of the freed block, only needed for the log
+    fn notify_free(&mut self, ptr: *mut u8, bytes: uint) {
+        self.parent.log_event(Freed, ptr, bytes);
+        match self.parent.on_free {
+            Some(hook) => hook(ptr),
+            None => {}
+        }
+    }
+
+    /// Free every allocated block that starts within the `size` bytes from
+    /// `ptr`, walking the range once rather than searching the tree for
+    /// each block. Reserved blocks are left alone. Returns the number of
+    /// blocks freed.
+    pub fn free_range(&mut self, ptr: *mut u8, size: uint) -> uint {
+        if size == 0 {
+            return 0;
+        }
+        // Inclusive bounds, as in `reserve_range`
+        let base = self.base as uint;
+        let arena_last = self.last_addr();
+        let last = ptr as uint + (size - 1);
+        let last = if last < ptr as uint || last > arena_last { arena_last } else { last };
+        if last < base {
+            return 0;
+        }
+        let first = if ptr < self.base { 0 } else { self.units_for(ptr as uint - base) };
+        let end = ((last - base) >> self.el_size) + 1;
+
+        let mut freed = 0;
+        let mut from = first;
+        while from < end {
+            let (_, level, left, state) = self.parent.cover(from);
+            let units = 1 << level;
+            match state {
+                USED if left >= first && !self.parent.is_reserved(left, units) => {
+                    let block = self.ptr_at(left);
+                    self.retire(left);
+                    self.parent.free_sized(left, units);
+                    self.notify_free(block, units << self.el_size);
+                    freed += 1;
+                }
+                _ => {}
+            }
+            from = left + units;
+        }
+        freed
+    }
+
+    // Check the canary of the block `ptr` was handed out for and place a
+    // new one for a resize to `size` units in place.
+    fn move_canary(&mut self, ptr: *mut u8, size: uint) {
+        if self.canaries as uint == 0 {
+            return;
+        }
+        let block = match self.block_offset(ptr) {
+            Some(offset) => self.parent.block_at(offset),
+            None => None
+        };
+        match block {
+            Some((start, units)) => {
+                self.check_canary(start);
+                let end = self.base as uint + ((start + units) << self.el_size);
+                self.place_canary(start, ptr as uint + (size << self.el_size), end);
+            }
+            None => {}
+        }
+    }
+
+    // Check and fill the block starting at `offset`, if it is an allocated
+    // block, as configured for freed memory, and forget its pad. Reserved
+    // blocks are never freed, so they are left as they are.
+    fn retire(&mut self, offset: uint) {
+        let size = match self.parent.block_at(offset) {
+            Some((start, size)) if start == offset => size,
+            _ => return
+        };
+        if self.parent.is_reserved(offset, size) {
+            return;
+        }
+        self.unpad(offset);
+        self.check_canary(offset);
+        self.scrub(offset, offset + size);
+    }
+
+    // Fill the units in `[from, to)`, which are being freed, as configured
+    // for freed memory, and track them in the dirty map.
+    fn scrub(&mut self, from: uint, to: uint) {
+        let zero_on_free = self.parent.zero_on_free;
+        self.mark_dirty(from, to, !zero_on_free);
+
+        let fill = if zero_on_free {
+            0
+        }
+        else if self.parent.poison_on_free {
+            POISON_BYTE
+        }
+        else {
+            return;
+        };
+        unsafe {
+            let ptr = self.ptr_at(from);
+            set_memory(ptr, fill, (to - from) << self.el_size);
+        }
+    }
+
+    /// Keep the `size` bytes from `start` from ever being handed out,
+    /// rounded out to whole units. Parts of the range outside the arena
+    /// are ignored. See `BuddyAlloc::reserve`.
+    pub fn reserve_range(&mut self, start: *mut u8, size: uint) -> bool {
+        if size == 0 {
+            return true;
+        }
+        // Inclusive bounds, as the arena may end at the top of memory
+        let base = self.base as uint;
+        let last = self.last_addr();
+        let lo = if (start as uint) < base { base } else { start as uint };
+        let hi = start as uint + (size - 1);
+        let hi = if hi < start as uint || hi > last { last } else { hi };
+        if lo > hi {
+            return true;
+        }
+        let first = (lo - base) >> self.el_size;
+        let end = ((hi - base) >> self.el_size) + 1;
+        self.parent.reserve(first, end - first)
+    }
+
+    /// Keep blocks from reaching past `end`, for arenas rounded up to a
+    /// power of two beyond the memory actually behind them. The space from
+    /// `end` on is reserved, so it counts as used and survives `reset`.
+    pub fn set_usable_end(&mut self, end: *mut u8) -> bool {
+        let last = self.last_addr();
+        if end as uint > last {
+            return true;
+        }
+        let start = if end < self.base { self.base } else { end };
+        self.reserve_range(start, last - start as uint + 1)
+    }
+
+    /// Reserve every region of a memory map not marked usable. Regions may
+    /// overlap or lie partly or wholly outside the arena. Returns false if
+    /// any reservation couldn't be recorded (see `BuddyAlloc::reserve`).
+    pub fn reserve_regions(&mut self, regions: &[MemoryRegion]) -> bool {
+        let mut ok = true;
+        for r in slice::iter(regions) {
+            if !r.usable && !self.reserve_range(r.start, r.size) {
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Bytes of the whole arena, reserved ranges included
+    pub fn capacity(&self) -> uint {
+        self.parent.capacity() << self.el_size
+    }
+
+    /// Bytes currently allocated, counting each block at its full size.
+    pub fn used_bytes(&self) -> uint {
+        self.parent.used() << self.el_size
+    }
+
+    pub fn peak_used_bytes(&self) -> uint {
+        self.parent.peak_used() << self.el_size
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.parent.reset_peak();
+    }
+
+    /// Bytes not currently allocated. This is a total over possibly
+    /// scattered free blocks, not the largest allocation that can succeed.
+    pub fn free_bytes(&self) -> uint {
+        self.parent.unused() << self.el_size
+    }
+
+    pub fn memory_map<'a>(&'a self) -> MemoryMap<'a> {
+        MemoryMap {
+            extents: self.parent.extents(),
+            base: self.base,
+            el_size: self.el_size
+        }
+    }
+
+    pub fn live_allocations<'a>(&'a self) -> LiveAllocations<'a> {
+        LiveAllocations {
+            extents: self.parent.extents(),
+            base: self.base,
+            el_size: self.el_size
+        }
+    }
+
+    /// Record the start of every live block in `buffer`, in address order,
+    /// for comparing with `snapshot_diff` later. Returns the number of
+    /// live blocks, which is more than `buffer.len()` if only the first
+    /// ones fit.
+    pub fn snapshot(&self, buffer: &mut [*mut u8]) -> uint {
+        let mut count = 0;
+        for (ptr, _) in self.live_allocations() {
+            if count < buffer.len() {
+                buffer[count] = ptr;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Bytes in the largest block that can currently be allocated.
+    pub fn largest_free_block(&self) -> uint {
+        self.parent.largest_free() << self.el_size
+    }
+
+    /// Allocate one page, returning null when it can't be.
+    pub fn alloc_page(&mut self) -> *mut u8 {
+        match self.alloc_pages(1) {
+            Ok((ptr, _)) => ptr,
+            Err(_) => 0 as *mut u8
+        }
+    }
+
+    /// Allocate `count` pages rounded up to a power of two. Pages that
+    /// couldn't fit in the arena, or whose size overflows, are
+    /// `SizeTooLarge`. Off a base that isn't page-aligned each run of pages
+    /// is padded, and once `MAX_PADDED` are out the next is `TooManyPadded`
+    /// however much memory is free. No pages at all take no block and give
+    /// the page-aligned `zero_size_ptr(PAGE_SIZE)`.
+    pub fn alloc_pages(&mut self, count: uint) -> Result<(*mut u8, uint), AllocError> {
+        if count == 0 {
+            return Ok((zero_size_ptr(PAGE_SIZE), 0));
+        }
+        let units = match mul_with_overflow(count, PAGE_SIZE) {
+            (_, true) => return Err(SizeTooLarge),
+            (bytes, _) => self.units_for(bytes)
+        };
+        // As `alloc_opt` would round it, with its pad
+        let pad = self.align_pad(PAGE_SIZE);
+        let align_units = PAGE_SIZE >> self.el_size;
+        let align_units = if align_units > 1 { align_units } else { 1 };
+        if units > 1 << self.parent.order ||
+                level_for(units + self.units_for(pad), align_units) > self.parent.order {
+            return Err(SizeTooLarge);
+        }
+        if pad != 0 && self.padded_count == MAX_PADDED {
+            return Err(TooManyPadded);
+        }
+        match unsafe { self.alloc_opt(units, PAGE_SIZE) } {
+            Some((ptr, size)) => {
+                assert(ptr as uint & (PAGE_SIZE - 1) == 0);
+                Ok((ptr, size))
+            }
+            None => Err(OutOfMemory)
+        }
+    }
+
+    /// Allocate `size` units on whole cache lines of their own. The usable
+    /// size is trimmed to a line multiple so it never reaches a line that
+    /// a neighboring block starts on.
+    pub fn alloc_cache_aligned(&mut self, size: uint) -> (*mut u8, uint) {
+        let bytes = ((size << self.el_size) + CACHE_LINE_SIZE - 1) & !(CACHE_LINE_SIZE - 1);
+        let units = (bytes + (1 << self.el_size) - 1) >> self.el_size;
+        match unsafe { self.alloc_align(units, CACHE_LINE_SIZE) } {
+            (ptr, 0) => (ptr, 0),
+            (ptr, size) => (ptr, size & !(CACHE_LINE_SIZE - 1))
+        }
+    }
+}
+
+impl Allocator for Alloc {
+    fn try_alloc(&mut self, size: uint) -> Result<(*mut u8, uint), AllocError> {
+        if size > 1 << self.parent.order {
+            return Err(SizeTooLarge);
+        }
+        match unsafe { self.alloc_opt(size, 1) } {
+            None => Err(OutOfMemory),
+            Some(r) => Ok(r)
+        }
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 || is_zero_size(src) {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+
+        let old_size = self.size_from(src);
+        if old_size != 0 && size << self.el_size <= old_size {
+            // Still fits in the current block
+            self.move_canary(src, size);
+            return (src, old_size);
+        }
+        if self.grow_in_place(src, size) {
+            return (src, self.size_from(src));
+        }
+
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                let count = if old_size < sz { old_size } else { sz };
+                unsafe { copy_memory(ptr, src as *u8, count); }
+                self.free(src);
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn zero_alloc(&mut self, size: uint) -> (*mut u8, uint) {
+        match unsafe { self.zero_alloc_opt(size, 1) } {
+            None => (self.base, 0),
+            Some(r) => r
+        }
+    }
+
+    // Compatibility shim: `(base, 0)` signals out of memory.
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        match self.alloc_opt(size, align) {
+            None => (self.base, 0),
+            Some(r) => r
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if ptr as uint == 0 || is_zero_size(ptr) {
+            return;
+        }
+        match self.block_offset(ptr) {
+            Some(offset) => {
+                let bytes = self.logged_size(offset);
+                self.retire(offset);
+                match self.parent.free_checked(offset) {
+                    Ok(()) => self.notify_free(ptr, bytes),
+                    // As in `BuddyAlloc::free`
+                    Err(_) if cfg!(not(ndebug)) => abort(),
+                    Err(_) => {}
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        match self.offset_of(ptr) {
+            Some(_) => true,
+            None => false
+        }
+    }
+
+    // Any pointer into a block works, and for a pointer `alloc` returned
+    // this is the rounded-up size it reported.
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        self.size_from(ptr)
+    }
+}
+
+impl<'a> Extents<'a> {
+    // Next used or unused block in address order
+    fn block(&mut self) -> Option<(uint, uint, Node)> {
+        if self.done {
+            return None;
+        }
+        loop {
+            match self.alloc.get(self.index) {
+                SPLIT | FULL => {
+                    self.index = self.index * 2 + 1; // left child
+                    self.level -= 1;
+                }
+                state => {
+                    let block = (self.offset, 1 << self.level, state);
+                    self.offset += 1 << self.level;
+
+                    // Go up past right children, then to the right sibling
+                    while self.index & 1 == 0 {
+                        if self.index == 0 {
+                            self.done = true;
+                            return Some(block);
+                        }
+                        self.index = (self.index + 1) / 2 - 1; // parent
+                        self.level += 1;
+                    }
+                    self.index += 1;
+                    return Some(block);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator<(uint, uint, Node)> for Extents<'a> {
+    fn next(&mut self) -> Option<(uint, uint, Node)> {
+        let first = match self.pending {
+            Some(block) => {
+                self.pending = None;
+                Some(block)
+            }
+            None => self.block()
+        };
+        let (offset, mut size, state) = match first {
+            Some(block) => block,
+            None => return None
+        };
+
+        loop {
+            match self.block() {
+                Some((_, len, next)) if next as u8 == state as u8 => size += len,
+                next => {
+                    self.pending = next;
+                    return Some((offset, size, state));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator<AllocEvent> for Events<'a> {
+    fn next(&mut self) -> Option<AllocEvent> {
+        let alloc = self.alloc;
+        if self.next >= alloc.event_count {
+            return None;
+        }
+        let event = unsafe {
+            *mut_offset(alloc.event_log, (self.next % alloc.event_capacity) as int)
+        };
+        self.next += 1;
+        Some(event)
+    }
+}
+
+impl<'a> Iterator<(*mut u8, uint, Node)> for MemoryMap<'a> {
+    fn next(&mut self) -> Option<(*mut u8, uint, Node)> {
+        match self.extents.next() {
+            Some((offset, size, state)) => Some((
+                (self.base as uint + (offset << self.el_size)) as *mut u8,
+                size << self.el_size,
+                state
+            )),
+            None => None
+        }
+    }
+}
+
+impl<'a> Iterator<(*mut u8, uint)> for LiveAllocations<'a> {
+    fn next(&mut self) -> Option<(*mut u8, uint)> {
+        loop {
+            match self.extents.block() {
+                Some((offset, size, USED)) if !self.extents.alloc.is_reserved(offset, size) => {
+                    return Some((
+                        (self.base as uint + (offset << self.el_size)) as *mut u8,
+                        size << self.el_size
+                    ));
+                }
+                Some(_) => {}
+                None => return None
+            }
+        }
+    }
+}
+
+/// The tree as `dump_tree` prints it, then a line of used, free and
+/// largest free units. Debug builds only.
+#[cfg(not(ndebug))]
+impl Show for BuddyAlloc {
+    fn fmt<W: Writer>(&self, w: &mut W) {
+        self.write_tree(w);
+        w.puts("used ");
+        w.putuint(self.used(), 10);
+        w.puts(" free ");
+        w.putuint(self.unused(), 10);
+        w.puts(" largest ");
+        w.putuint(self.largest_free(), 10);
+        w.puts("\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::min_align_of;
+    use std::str::from_utf8;
+    use cpu::mmu::PAGE_SIZE;
+    use kernel::mm::{Allocator, Alloc, AllocResult, MemoryRegion, RightFirst};
+    use kernel::mm::{ZERO_SIZE_PTR, is_zero_size};
+    use kernel::mm::{AllocEvent, Allocated, Freed, heap_init, snapshot_diff};
+    use kernel::mm::{DoubleFree, InteriorPointer, SizeTooLarge, OutOfMemory, TooManyPadded};
+    use kernel::mm::allocator::{UNUSED, USED, SPLIT, FULL, MAX_LEVELS, MAX_PADDED, NIL, POISON_BYTE};
+    use kernel::mm::testing::{Arena, Region, Rng, buddy, peek, fill};
+    use util::bitv::Bitv;
+    use util::fmt::{Buffer, Show};
+    use util::ptr::mut_offset;
+
+    #[test]
+    fn buddy_alloc_runs_on_host() {
+        let (mut b, _tree) = buddy(4);
+        assert_eq!(b.alloc(3), Some((0, 4)));
+        assert_eq!(b.alloc(1), Some((4, 1)));
+        assert_eq!(b.check_invariants(), Ok(()));
+        b.free(0);
+        b.free(4);
+        assert_eq!(b.used(), 0);
+        assert_eq!(b.largest_free(), 16);
+    }
+
+    #[test]
+    fn arena_memory_is_usable() {
+        let mut arena = Arena::bytes(12);
+        let a = &mut arena.alloc;
+        let (ptr, size) = a.alloc(100);
+        assert_eq!(size, 128);
+        unsafe {
+            *ptr = 1;
+            *((ptr as uint + 127) as *mut u8) = 2;
+        }
+        a.free(ptr);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn alloc_aligned_offsets_are_multiples_of_align() {
+        let (mut b, _tree) = buddy(14);
+        // Leave the next free offset unaligned
+        b.alloc(1);
+        for &align in [16u, 256, 4096].iter() {
+            match b.alloc_aligned(1, align) {
+                Some((offset, size)) => {
+                    assert_eq!(offset & (align - 1), 0);
+                    assert_eq!(size, align);
+                }
+                None => fail!("out of memory")
+            }
+        }
+        assert_eq!(b.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn over_aligned_alloc_frees_cleanly() {
+        let mut arena = Arena::bytes(14);
+        let a = &mut arena.alloc;
+        a.alloc(16);
+        let (ptr, size) = unsafe { a.alloc_align(100, 4096) };
+        assert_eq!(ptr as uint & 4095, 0);
+        assert_eq!(size, 4096);
+        a.free(ptr);
+        assert_eq!(a.used_bytes(), 16);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn over_aligned_alloc_pads_into_its_block_off_an_unaligned_base() {
+        let memory = Region::new((1 << 14) + 16, 4096);
+        let (bitv, tree) = Bitv::on_host(14);
+        let base = (memory.start as uint + 16) as *mut u8;
+        let mut a = Alloc::new(base, 14, 0, bitv, tree.len() * 4);
+        let (ptr, size) = unsafe { a.alloc_align(100, 4096) };
+        assert_eq!(ptr as uint & 4095, 0);
+        assert!(size >= 100);
+        // The whole padded block is taken, and freeing `ptr` finds it
+        assert_eq!(a.used_bytes(), 8192);
+        a.free(ptr);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn cache_aligned_allocations_never_share_a_line() {
+        let mut arena = Arena::bytes(12);
+        let a = &mut arena.alloc;
+        let (p, p_size) = a.alloc_cache_aligned(10);
+        let (q, q_size) = a.alloc_cache_aligned(10);
+        for &(ptr, size) in [(p, p_size), (q, q_size)].iter() {
+            assert_eq!(ptr as uint & 63, 0);
+            assert!(size >= 10);
+            assert_eq!(size & 63, 0);
+        }
+        // The lines each block covers are disjoint
+        let (p, q) = (p as uint, q as uint);
+        assert!(p + p_size <= q || q + q_size <= p);
+        assert!((p + p_size - 1) >> 6 != q >> 6 && (q + q_size - 1) >> 6 != p >> 6);
+    }
+
+    #[test]
+    fn zero_sized_alloc_frees_as_a_no_op() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (live, _) = a.alloc(16);
+        let (ptr, size) = a.alloc(0);
+        assert_eq!(ptr as uint, ZERO_SIZE_PTR as uint);
+        assert_eq!(size, 0);
+        // Larger alignments get their own address, still outside the arena
+        let (wide, size) = unsafe { a.alloc_align(0, 256) };
+        assert_eq!(wide as uint, 256);
+        assert_eq!(size, 0);
+        assert!(!a.owns(wide));
+
+        a.free(ptr);
+        assert!(a.free_checked(ptr).is_ok());
+        a.free(wide);
+        assert!(a.free_checked(wide).is_ok());
+        assert_eq!(a.used_bytes(), 16);
+        a.free(live);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn zero_sized_alloc_can_be_page_aligned() {
+        let mut arena = Arena::new(4, 12);
+        let a = &mut arena.alloc;
+        let (ptr, size) = unsafe { a.alloc_align(0, PAGE_SIZE) };
+        assert_eq!(size, 0);
+        assert_eq!(ptr as uint & (PAGE_SIZE - 1), 0);
+        assert!(!a.owns(ptr));
+        let wide = unsafe { a.alloc_layout(0, 1 << 20) };
+        assert_eq!(wide as uint & ((1 << 20) - 1), 0);
+        assert!(is_zero_size(wide));
+
+        // Neither takes a block, and both are accepted back
+        assert_eq!(a.used_bytes(), 0);
+        a.free(ptr);
+        assert!(a.free_checked(wide).is_ok());
+        let (p, size) = a.realloc(ptr, 1);
+        assert!(a.owns(p));
+        assert_eq!(size, PAGE_SIZE);
+        unsafe { a.dealloc_layout(wide, 0, 1 << 20); }
+        assert_eq!(a.used_bytes(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn realloc_of_the_zero_sized_pointer_allocates() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(0);
+        let (ptr, size) = a.realloc(ptr, 24);
+        assert!(a.owns(ptr));
+        assert_eq!(size, 32);
+        assert_eq!(a.used_bytes(), 32);
+        let (wide, _) = unsafe { a.alloc_align(0, 64) };
+        let (wide, size) = a.realloc(wide, 24);
+        assert!(a.owns(wide));
+        assert_eq!(size, 32);
+    }
+
+    #[test]
+    fn realloc_copies_only_the_old_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (small, _) = a.alloc(16);
+        let (next, _) = a.alloc(16);
+        fill(small, 0xaa, 16);
+        fill(next, 0xbb, 16);
+
+        // `next` keeps `small` from growing in place, so it moves
+        let (moved, size) = a.realloc(small, 64);
+        assert!(moved != small);
+        assert_eq!(size, 64);
+        for i in range(0u, 16) {
+            assert_eq!(peek(moved, i), 0xaa);
+        }
+        // Nothing past the old block, such as `next`, was copied
+        for i in range(16u, 64) {
+            assert_eq!(peek(moved, i), 0);
+        }
+        assert_eq!(peek(next, 0), 0xbb);
+    }
+
+    #[test]
+    fn shrinking_realloc_keeps_the_pointer() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, size) = a.alloc(100);
+        assert_eq!(size, 128);
+        assert_eq!(a.realloc(ptr, 50), (ptr, 128));
+        assert_eq!(a.realloc(ptr, 128), (ptr, 128));
+        assert_eq!(a.used_bytes(), 128);
+    }
+
+    #[test]
+    fn realloc_grows_into_a_free_buddy() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(16);
+        fill(ptr, 0xaa, 16);
+        assert_eq!(a.realloc(ptr, 48), (ptr, 64));
+        assert_eq!(peek(ptr, 15), 0xaa);
+        assert_eq!(a.used_bytes(), 64);
+        assert_eq!(a.parent.allocation_count(), 1);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn realloc_moves_when_the_buddy_is_taken() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(16);
+        let (buddy, _) = a.alloc(16);
+        fill(ptr, 0xaa, 16);
+        let (moved, size) = a.realloc(ptr, 32);
+        assert!(moved != ptr);
+        assert_eq!(size, 32);
+        assert_eq!(peek(moved, 0), 0xaa);
+        assert_eq!(peek(moved, 15), 0xaa);
+        // The old block was freed and the buddy is untouched
+        assert_eq!(a.usable_size(ptr), 0);
+        assert_eq!(a.usable_size(buddy), 16);
+        assert_eq!(a.used_bytes(), 48);
+    }
+
+    #[test]
+    fn realloc_of_null_allocates() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, size) = a.realloc(0 as *mut u8, 24);
+        assert!(a.owns(ptr));
+        assert_eq!(size, 32);
+        assert_eq!(a.usable_size(ptr), 32);
+        assert_eq!(a.used_bytes(), 32);
+    }
+
+    #[test]
+    fn realloc_to_zero_frees_the_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(100);
+        let (null, size) = a.realloc(ptr, 0);
+        assert_eq!(null as uint, 0);
+        assert_eq!(size, 0);
+        a.free(null);
+        // The whole arena is one free block again
+        assert_eq!(a.used_bytes(), 0);
+        assert_eq!(a.largest_free_block(), 1024);
+        assert_eq!(a.parent.free_counts()[10], 1);
+    }
+
+    #[test]
+    fn free_checked_reports_a_double_free() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(16);
+        let (other, _) = a.alloc(16);
+        assert!(a.free_checked(ptr).is_ok());
+        match a.free_checked(ptr) {
+            Err(DoubleFree) => {}
+            _ => fail!("double free not reported")
+        }
+        // The failed free changed nothing
+        assert_eq!(a.usable_size(other), 16);
+        assert_eq!(a.used_bytes(), 16);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn buddy_free_checked_reports_a_double_free() {
+        let (mut b, _tree) = buddy(4);
+        let (offset, _) = b.alloc(2).unwrap();
+        assert!(b.free_checked(offset).is_ok());
+        match b.free_checked(offset) {
+            Err(DoubleFree) => {}
+            _ => fail!("double free not reported")
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn double_free_aborts_in_debug_builds() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(16);
+        a.free(ptr);
+        a.free(ptr);
+    }
+
+    #[test]
+    fn free_checked_rejects_an_interior_pointer() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(64);
+        fill(ptr, 0xaa, 64);
+        a.parent.set_poison_on_free(true);
+        match a.free_checked((ptr as uint + 8) as *mut u8) {
+            Err(InteriorPointer) => {}
+            _ => fail!("interior pointer accepted")
+        }
+        // The block is still allocated and wasn't poisoned
+        assert_eq!(a.usable_size(ptr), 64);
+        assert_eq!(peek(ptr, 8), 0xaa);
+        assert!(a.free_checked(ptr).is_ok());
+    }
+
+    #[test]
+    fn free_checked_tells_padding_from_an_interior_pointer_off_an_unaligned_base() {
+        let memory = Region::new((1 << 14) + 16, 4096);
+        let (bitv, tree) = Bitv::on_host(14);
+        let base = (memory.start as uint + 16) as *mut u8;
+        let mut a = Alloc::new(base, 14, 0, bitv, tree.len() * 4);
+        // An unpadded block with a page-aligned pointer where an aligned
+        // allocation's pad would end
+        let (ptr, _) = unsafe { a.alloc_align(8192, 1) };
+        assert_eq!(ptr, base);
+        let inner = (ptr as uint + 4080) as *mut u8;
+        assert_eq!(inner as uint & 4095, 0);
+        match a.free_checked(inner) {
+            Err(InteriorPointer) => {}
+            _ => fail!("interior pointer taken for padding")
+        }
+        assert_eq!(a.used_bytes(), 8192);
+
+        let (padded, _) = unsafe { a.alloc_align(100, 4096) };
+        assert_eq!(padded as uint & 4095, 0);
+        assert!(a.free_checked(padded).is_ok());
+        assert!(a.free_checked(ptr).is_ok());
+        assert_eq!(a.used_bytes(), 0);
+        assert_eq!(a.padded_count, 0);
+    }
+
+    #[test]
+    fn buddy_free_checked_rejects_an_interior_offset() {
+        let (mut b, _tree) = buddy(4);
+        let (offset, _) = b.alloc(4).unwrap();
+        match b.free_checked(offset + 1) {
+            Err(InteriorPointer) => {}
+            _ => fail!("interior offset accepted")
+        }
+        assert_eq!(b.used(), 4);
+    }
+
+    #[test]
+    fn free_sized_leaves_the_same_tree_as_free() {
+        let (mut by_search, search_tree) = buddy(10);
+        let (mut by_size, size_tree) = buddy(10);
+        let mut rng = Rng::new(19);
+        let mut live = [(0u, 0u), ..64];
+        let mut count = 0;
+
+        let mut step = 0;
+        while step < 2000 {
+            if count < live.len() && (count == 0 || rng.below(2) == 0) {
+                let size = rng.below(64) + 1;
+                let block = by_search.alloc(size);
+                assert_eq!(by_size.alloc(size), block);
+                match block {
+                    Some(block) => {
+                        live[count] = block;
+                        count += 1;
+                    }
+                    None => {}
+                }
+            }
+            else {
+                let i = rng.below(count);
+                let (offset, size) = live[i];
+                count -= 1;
+                live[i] = live[count];
+                by_search.free(offset);
+                by_size.free_sized(offset, size);
+            }
+            assert!(search_tree == size_tree);
+            assert_eq!(by_search.used(), by_size.used());
+            step += 1;
+        }
+        assert_eq!(by_size.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn used_bytes_counts_whole_blocks() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        a.alloc(3);
+        a.alloc(10);
+        a.alloc(100);
+        assert_eq!(a.used_bytes(), 4 + 16 + 128);
+    }
+
+    #[test]
+    fn free_and_used_bytes_add_up_to_the_arena() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        a.alloc(512);
+        assert_eq!(a.free_bytes(), 512);
+        a.alloc(5);
+        a.alloc(60);
+        assert_eq!(a.free_bytes() + a.used_bytes(), 1 << 10);
+    }
+
+    #[test]
+    fn largest_free_block_shrinks_as_the_arena_splits() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        assert_eq!(a.largest_free_block(), 1024);
+        a.alloc(1);
+        assert_eq!(a.largest_free_block(), 512);
+        a.alloc(512);
+        assert_eq!(a.largest_free_block(), 256);
+        a.alloc(256);
+        assert_eq!(a.largest_free_block(), 128);
+    }
+
+    #[test]
+    fn fragmentation_is_zero_while_free_space_is_one_block() {
+        let (mut b, _tree) = buddy(4);
+        assert_eq!(b.fragmentation_ratio(), 0);
+        b.alloc(8);
+        assert_eq!(b.fragmentation_ratio(), 0);
+        // A full tree has no free space to fragment
+        b.alloc(8);
+        assert_eq!(b.fragmentation_ratio(), 0);
+    }
+
+    #[test]
+    fn fragmentation_is_highest_with_every_other_leaf_free() {
+        let (mut b, _tree) = buddy(4);
+        let mut i = 0;
+        while i < 16 {
+            b.alloc(1);
+            i += 1;
+        }
+        i = 0;
+        while i < 16 {
+            b.free(i);
+            i += 2;
+        }
+        // Eight free units, the largest block one of them
+        assert_eq!(b.unused(), 8);
+        assert_eq!(b.largest_free(), 1);
+        assert_eq!(b.fragmentation_ratio(), 10000 - 10000 / 8);
+    }
+
+    #[test]
+    fn allocation_count_returns_to_zero() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut ptrs = [0 as *mut u8, ..10];
+        let mut i = 0;
+        while i < ptrs.len() {
+            let (ptr, _) = a.alloc(i + 1);
+            ptrs[i] = ptr;
+            i += 1;
+            assert_eq!(a.parent.allocation_count(), i);
+        }
+        // Resizing keeps the count
+        let (ptr, _) = a.realloc(ptrs[0], 200);
+        ptrs[0] = ptr;
+        assert_eq!(a.parent.allocation_count(), 10);
+        for &ptr in ptrs.iter() {
+            a.free(ptr);
+        }
+        assert_eq!(a.parent.allocation_count(), 0);
+    }
+
+    #[test]
+    fn memory_map_yields_free_used_free() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        a.alloc_at((base + 512) as *mut u8, 256);
+
+        let mut map = a.memory_map();
+        let expected = [(base, 512, UNUSED), (base + 512, 256, USED), (base + 768, 256, UNUSED)];
+        for &(start, size, state) in expected.iter() {
+            match map.next() {
+                Some((ptr, len, s)) => {
+                    assert_eq!(ptr as uint, start);
+                    assert_eq!(len, size);
+                    assert_eq!(s as u8, state as u8);
+                }
+                None => fail!("map ended early")
+            }
+        }
+        assert!(map.next().is_none());
+    }
+
+    #[test]
+    fn peak_usage_keeps_the_high_point() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (ptr, _) = a.alloc(512);
+        a.free(ptr);
+        a.alloc(64);
+        assert_eq!(a.used_bytes(), 64);
+        assert_eq!(a.peak_used_bytes(), 512);
+        a.reset_peak();
+        assert_eq!(a.peak_used_bytes(), 64);
+    }
+
+    #[test]
+    fn free_counts_shift_down_a_level_on_a_split() {
+        let (mut b, _tree) = buddy(4);
+        let counts = b.free_counts();
+        assert_eq!(counts[4], 1);
+        assert_eq!(counts[3], 0);
+        b.alloc(8);
+        let counts = b.free_counts();
+        assert_eq!(counts[4], 0);
+        assert_eq!(counts[3], 1);
+    }
+
+    #[test]
+    fn iter_nodes_walks_the_tree_in_order() {
+        let (mut b, _tree) = buddy(2);
+        b.alloc(1);
+        let expected = [SPLIT, SPLIT, UNUSED, USED, UNUSED, UNUSED, UNUSED];
+        let mut count = 0;
+        for (i, x) in b.tree.iter_nodes() {
+            assert_eq!(i, count);
+            assert_eq!(x, expected[i] as u8);
+            count += 1;
+        }
+        assert_eq!(count, 7);
+    }
+
+    #[test]
+    fn poison_covers_the_whole_freed_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        a.parent.set_poison_on_free(true);
+        let (p, size) = a.alloc(100);
+        assert_eq!(size, 128);
+        fill(p, 0, size);
+        a.free(p);
+        for i in range(0u, 128) {
+            assert_eq!(peek(p, i), POISON_BYTE);
+        }
+        // and stops at its end
+        assert_eq!(peek(p, 128), 0);
+    }
+
+    #[test]
+    fn zero_on_free_leaves_freed_blocks_zeroed() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        // as if built by `BuddyAlloc::new(.., true)`; it wins over poisoning
+        a.parent.zero_on_free = true;
+        a.parent.set_poison_on_free(true);
+        let (p, size) = a.alloc(64);
+        fill(p, 0xAB, size);
+        a.free(p);
+        let (q, _) = a.alloc(64);
+        assert_eq!(q as uint, p as uint);
+        for i in range(0u, 64) {
+            assert_eq!(peek(q, i), 0);
+        }
+    }
+
+    #[test]
+    fn canaries_pass_blocks_used_within_bounds() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut table = Vec::from_elem(1 << 10, 0u);
+        unsafe { a.set_canaries(table.as_mut_ptr()); }
+        let (p, _) = a.alloc(100);
+        fill(p, 0xAB, 100);
+        a.free(p);
+        // No room for a canary past a block used to its end
+        let (q, size) = a.alloc(128);
+        fill(q, 0xAB, size);
+        a.free(q);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    #[should_fail]
+    fn canaries_catch_an_overrun() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut table = Vec::from_elem(1 << 10, 0u);
+        unsafe { a.set_canaries(table.as_mut_ptr()); }
+        let (p, _) = a.alloc(100);
+        fill(p, 0xAB, 101);
+        a.free(p);
+    }
+
+    #[test]
+    fn reserved_middle_chunk_is_skipped() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        assert!(a.reserve_range((base + 256) as *mut u8, 256));
+        assert_eq!(a.free_bytes(), 768);
+        let mut count = 0;
+        loop {
+            match a.alloc(64) {
+                (_, 0) => break,
+                (p, _) => {
+                    let offset = p as uint - base;
+                    assert!(offset < 256 || offset >= 512);
+                    count += 1;
+                }
+            }
+        }
+        assert_eq!(count, 12);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn reserve_regions_takes_a_mixed_map() {
+        let mut arena = Arena::new(6, 12);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let region = |start: uint, size: uint, usable: bool| {
+            MemoryRegion { start: start as *mut u8, size: size, usable: usable }
+        };
+        let map = [
+            // firmware data in the first page
+            region(base, 0x1000, false),
+            region(base + 0x1000, 0x3e000, true),
+            // the kernel image, overlapping the usable range
+            region(base + 0x10000, 0x8000, false),
+            // ACPI tables in part of a page
+            region(base + 0x20000, 0x800, false),
+            // MMIO straddling the end of the arena, and below it
+            region(base + 0x3f000, 0x2000, false),
+            region(base - 0x4000, 0x2000, false)
+        ];
+        assert!(a.reserve_regions(&map));
+
+        let mut taken = [false, ..64];
+        for &page in [0u, 16, 17, 18, 19, 20, 21, 22, 23, 32, 63].iter() {
+            taken[page] = true;
+        }
+        assert_eq!(a.free_bytes(), (64 - 11) << 12);
+        let mut count = 0;
+        loop {
+            match a.alloc(1) {
+                (_, 0) => break,
+                (p, _) => {
+                    assert!(!taken[(p as uint - base) >> 12]);
+                    count += 1;
+                }
+            }
+        }
+        assert_eq!(count, 64 - 11);
+    }
+
+    #[test]
+    fn check_invariants_reports_corrupted_trees() {
+        let (mut b, _tree) = buddy(2);
+        b.alloc(1);
+        assert_eq!(b.check_invariants(), Ok(()));
+
+        // Nodes 1, 3 and 4 are a parent and its two leaves
+        b.tree.set(3, UNUSED as u8);
+        assert_eq!(b.check_invariants(), Err("split node with both children unused"));
+        b.tree.set(3, USED as u8);
+        b.tree.set(4, USED as u8);
+        assert_eq!(b.check_invariants(), Err("split node with both children taken, should be full"));
+        b.tree.set(1, FULL as u8);
+        b.tree.set(4, UNUSED as u8);
+        assert_eq!(b.check_invariants(), Err("full node with a child that isn't taken"));
+        b.tree.set(1, SPLIT as u8);
+        assert_eq!(b.check_invariants(), Ok(()));
+
+        b.used += 1;
+        assert_eq!(b.check_invariants(), Err("used count doesn't match used blocks"));
+    }
+
+    // Most blocks `fuzz` keeps live at once
+    static FUZZ_LIVE: uint = 64;
+
+    fn fuzz_fail(seed: u32, step: uint, what: &str) -> ! {
+        fail!("fuzz seed {} failed at step {}: {}", seed, step, what)
+    }
+
+    // Whether the first `len` bytes at `ptr` all hold `byte`
+    fn holds(ptr: *mut u8, byte: u8, len: uint) -> bool {
+        let mut i = 0;
+        while i < len {
+            if peek(ptr, i) != byte {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    // Random allocs, frees and reallocs from `seed`, checked against the live
+    // blocks after each one. Each block is filled with its own byte, so a
+    // block handed out twice or a bad copy shows up when it is next touched.
+    fn fuzz(seed: u32, steps: uint, free_lists: bool) {
+        let mut arena = Arena::bytes(12);
+        let a = &mut arena.alloc;
+        a.set_free_lists(free_lists);
+        let mut rng = Rng::new(seed);
+        // (pointer, bytes asked for, fill byte)
+        let mut live = [(0 as *mut u8, 0u, 0u8), ..FUZZ_LIVE];
+        let mut count = 0;
+
+        let mut step = 0;
+        while step < steps {
+            let size = rng.below(300) + 1;
+            let byte = (step % 251) as u8 + 1;
+            match rng.below(3) {
+                0 if count < FUZZ_LIVE => match a.alloc(size) {
+                    (_, 0) => {}
+                    (ptr, got) => {
+                        if got < size {
+                            fuzz_fail(seed, step, "alloc returned too little");
+                        }
+                        fill(ptr, byte, size);
+                        live[count] = (ptr, size, byte);
+                        count += 1;
+                    }
+                },
+                1 if count > 0 => {
+                    let i = rng.below(count);
+                    let (ptr, len, old) = live[i];
+                    if !holds(ptr, old, len) {
+                        fuzz_fail(seed, step, "block overwritten before free");
+                    }
+                    a.free(ptr);
+                    count -= 1;
+                    live[i] = live[count];
+                }
+                _ if count > 0 => {
+                    let i = rng.below(count);
+                    let (ptr, len, old) = live[i];
+                    match a.realloc(ptr, size) {
+                        // `ptr` is still live and untouched
+                        (_, 0) => {}
+                        (moved, got) => {
+                            let kept = if len < size { len } else { size };
+                            if got < size || !holds(moved, old, kept) {
+                                fuzz_fail(seed, step, "realloc lost data");
+                            }
+                            fill(moved, byte, size);
+                            live[i] = (moved, size, byte);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            match a.parent.check_invariants() {
+                Ok(()) => {}
+                Err(e) => fuzz_fail(seed, step, e)
+            }
+            let mut used = 0;
+            let mut i = 0;
+            while i < count {
+                let (p, _, _) = live[i];
+                let (p_start, p_end) = (p as uint, p as uint + a.usable_size(p));
+                used += p_end - p_start;
+                let mut j = i + 1;
+                while j < count {
+                    let (q, _, _) = live[j];
+                    let (q_start, q_end) = (q as uint, q as uint + a.usable_size(q));
+                    if p_start < q_end && q_start < p_end {
+                        fuzz_fail(seed, step, "live blocks overlap");
+                    }
+                    j += 1;
+                }
+                i += 1;
+            }
+            if used != a.used_bytes() {
+                fuzz_fail(seed, step, "used bytes don't match the live blocks");
+            }
+            step += 1;
+        }
+    }
+
+    #[test]
+    fn fuzz_alloc_free_realloc() {
+        for &seed in [1u32, 58, 0xdeadbeef, 0x9e3779b9].iter() {
+            fuzz(seed, 3000, false);
+        }
+    }
+
+    #[test]
+    fn fuzz_with_free_lists() {
+        for &seed in [1u32, 74, 0xdeadbeef].iter() {
+            fuzz(seed, 3000, true);
+        }
+    }
+
+    #[test]
+    fn page_leaves_allocate_whole_pages_and_coalesce() {
+        let mut arena = Arena::new(4, 12);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        assert_eq!(a.min_block_size(), 4096);
+        let (p, size) = a.alloc(1);
+        assert_eq!(size, 4096);
+        let (q, size) = a.alloc(3);
+        assert_eq!(size, 4 * 4096);
+        assert_eq!((p as uint - base) % 4096, 0);
+        assert_eq!((q as uint - base) % (4 * 4096), 0);
+        assert_eq!(a.used_bytes(), 5 * 4096);
+
+        a.free(p);
+        a.free(q);
+        assert_eq!(a.largest_free_block(), 16 * 4096);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn new_scaled_bumps_the_leaf_size_for_huge_arenas() {
+        // 64 GiB at a made-up base; nothing here touches the arena's memory
+        let base = (1u << 40) as *mut u8;
+        let (bitv, tree) = Bitv::on_host(16);
+        let (mut a, leaf) = Alloc::new_scaled(base, 36, bitv, tree.len() * 4);
+        assert_eq!(leaf, 1 << 20);
+        assert_eq!(a.min_block_size(), 1 << 20);
+        assert_eq!(a.order(), 16);
+        assert_eq!(a.capacity(), 1 << 36);
+
+        let (p, size) = a.alloc(1);
+        assert_eq!((p as uint, size), (base as uint, 1 << 20));
+        let (q, size) = a.alloc(3);
+        assert_eq!(size, 4 << 20);
+        assert_eq!(q as uint, base as uint + (4 << 20));
+        a.free(p);
+        a.free(q);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn alloc_array_rejects_overflowing_sizes() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let count = !0u / 2 + 2;
+        assert!(a.alloc_array(count, 2).is_none());
+        assert!(a.zero_array(count, 2).is_none());
+        assert_eq!(a.used_bytes(), 0);
+        match a.alloc_array(10, 6) {
+            Some((_, size)) => assert_eq!(size, 64),
+            None => fail!("out of memory")
+        }
+    }
+
+    #[test]
+    fn alloc_pages_rejects_overflowing_counts() {
+        let mut arena = Arena::new(4, 12);
+        let a = &mut arena.alloc;
+        for &count in [!0u / PAGE_SIZE + 2, 17].iter() {
+            match a.alloc_pages(count) {
+                Err(SizeTooLarge) => {}
+                _ => fail!("expected SizeTooLarge")
+            }
+        }
+        assert_eq!(a.used_bytes(), 0);
+        match a.alloc_pages(3) {
+            Ok((ptr, size)) => {
+                assert_eq!(ptr as uint & (PAGE_SIZE - 1), 0);
+                assert_eq!(size, 4 * PAGE_SIZE);
+            }
+            Err(_) => fail!("out of memory")
+        }
+    }
+
+    #[test]
+    fn alloc_pages_off_an_unaligned_base_stops_at_max_padded() {
+        let memory = Region::new((1 << 18) + 16, 4096);
+        let (bitv, tree) = Bitv::on_host(18);
+        let base = (memory.start as uint + 16) as *mut u8;
+        let mut a = Alloc::new(base, 18, 0, bitv, tree.len() * 4);
+        let mut first = 0 as *mut u8;
+        for i in range(0u, MAX_PADDED) {
+            match a.alloc_pages(1) {
+                Ok((ptr, _)) => if i == 0 { first = ptr },
+                Err(_) => fail!("out of memory")
+            }
+        }
+        // Half the arena is still free
+        assert_eq!(a.used_bytes(), 1 << 17);
+        match a.alloc_pages(1) {
+            Err(TooManyPadded) => {}
+            _ => fail!("expected TooManyPadded")
+        }
+        a.free(first);
+        assert!(a.alloc_pages(1).is_ok());
+    }
+
+    #[test]
+    fn no_pages_take_no_block() {
+        let mut arena = Arena::new(4, 12);
+        let a = &mut arena.alloc;
+        match a.alloc_pages(0) {
+            Ok((ptr, size)) => {
+                assert_eq!(size, 0);
+                assert_eq!(ptr as uint & (PAGE_SIZE - 1), 0);
+                assert!(is_zero_size(ptr));
+                assert!(!a.owns(ptr));
+            }
+            Err(_) => fail!("no pages failed")
+        }
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn shrink_gives_back_the_tail() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(512);
+        a.alloc(512);
+        assert_eq!(p as uint, base);
+        assert_eq!(a.shrink_to(p, 100), 128);
+        assert_eq!(a.used_bytes(), 512 + 128);
+
+        // The freed quarters are allocatable again, in place
+        let (q, _) = a.alloc(256);
+        assert_eq!(q as uint, base + 256);
+        let (r, _) = a.alloc(128);
+        assert_eq!(r as uint, base + 128);
+        // A size that needs the whole block is a no-op
+        assert_eq!(a.shrink_to(p, 128), 128);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn alloc_at_takes_only_free_aligned_space() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let at = |offset: uint| (base + offset) as *mut u8;
+        assert_eq!(a.alloc_at(at(256), 200), Some((at(256), 256)));
+        // Taken now, so neither it nor a block covering it is free
+        assert_eq!(a.alloc_at(at(256), 256), None);
+        assert_eq!(a.alloc_at(at(0), 512), None);
+        // Not aligned to the rounded size
+        assert_eq!(a.alloc_at(at(128), 256), None);
+        assert_eq!(a.alloc_at(at(512), 512), Some((at(512), 512)));
+        assert_eq!(a.used_bytes(), 768);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn reset_keeps_only_the_pinned_block() {
+        let (mut b, _tree) = buddy(6);
+        b.alloc(4);
+        let (pinned, size) = b.alloc_pinned(8, 1).unwrap();
+        b.alloc(16);
+        b.alloc(1);
+        b.reset();
+        assert_eq!(b.used(), size);
+        assert_eq!(b.next_used(0), Some((pinned, size)));
+        assert_eq!(b.next_used(pinned + size), None);
+        assert_eq!(b.check_invariants(), Ok(()));
+
+        // Freeing unpins it
+        b.free(pinned);
+        b.reset();
+        assert_eq!(b.used(), 0);
+        assert_eq!(b.largest_free(), 64);
+    }
+
+    #[test]
+    fn reset_leaves_one_free_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut i = 0;
+        while i < 40 {
+            a.alloc(i % 7 + 1);
+            i += 1;
+        }
+        a.reset();
+        assert_eq!(a.used_bytes(), 0);
+        assert_eq!(a.parent.allocation_count(), 0);
+        assert_eq!(a.largest_free_block(), 1024);
+        assert_eq!(a.parent.free_counts()[10], 1);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn from_range_rounds_down_to_a_power_of_two() {
+        let memory = Region::new(3000, 16);
+        let (bitv, tree) = Bitv::on_host(11);
+        let mut a = Alloc::from_range(memory.start, memory.end(), bitv, tree.len() * 4);
+        assert_eq!(a.order(), 11);
+        assert_eq!(a.capacity(), 2048);
+        let (p, size) = a.alloc(2048);
+        assert_eq!((p as uint, size), (memory.start as uint, 2048));
+        // The tail past 2048 bytes isn't used
+        let (_, size) = a.alloc(1);
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn show_renders_the_tree_and_a_summary() {
+        let (mut b, _tree) = buddy(2);
+        b.alloc(1);
+        let mut out = [0u8, ..64];
+        let len = {
+            let mut w = Buffer::new(&mut out);
+            b.fmt(&mut w);
+            w.len()
+        };
+        assert_eq!(from_utf8(out.slice_to(len)).unwrap(),
+                   "2: S\n1: S U\n0: X U\nused 1 free 3 largest 2\n");
+    }
+
+    #[test]
+    fn live_allocations_yields_each_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(16);
+        let (q, _) = a.alloc(100);
+        let (r, _) = a.alloc(200);
+        // A freed block isn't listed
+        let (s, _) = a.alloc(8);
+        a.free(s);
+        let mut found = [false, false, false];
+        let mut count = 0;
+        for (ptr, size) in a.live_allocations() {
+            let i = match (ptr as uint, size) {
+                (x, 16) if x == p as uint => 0,
+                (x, 128) if x == q as uint => 1,
+                (x, 256) if x == r as uint => 2,
+                _ => fail!("unexpected block")
+            };
+            assert!(!found[i]);
+            found[i] = true;
+            count += 1;
+        }
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn offsets_stay_in_range_at_the_largest_order() {
+        // Only the order matters to `offset`, so the tree can stay small
+        let (mut b, _tree) = buddy(2);
+        let order = MAX_LEVELS - 1;
+        b.order = order;
+        assert_eq!(b.offset(0, order), 0);
+        assert_eq!(b.offset(1, order - 1), 0);
+        assert_eq!(b.offset(2, order - 1), 1 << (order - 1));
+        // first and last leaves
+        assert_eq!(b.offset((1 << order) - 1, 0), 0);
+        assert_eq!(b.offset((2 << order) - 2, 0), (1 << order) - 1);
+        assert_eq!(b.offset((1 << order) + 12344, 0), 12345);
+    }
+
+    #[test]
+    fn layout_allocations_round_trip() {
+        let mut arena = Arena::bytes(14);
+        let a = &mut arena.alloc;
+        for &(size, align) in [(1u, 1u), (24, 8), (100, 64), (3000, 4096), (5000, 16)].iter() {
+            unsafe {
+                let p = a.alloc_layout(size, align);
+                assert!(p as uint != 0);
+                assert_eq!(p as uint & (align - 1), 0);
+                assert!(a.usable_size(p) >= size);
+                a.dealloc_layout(p, size, align);
+            }
+            assert_eq!(a.used_bytes(), 0);
+            assert_eq!(a.parent.check_invariants(), Ok(()));
+        }
+        unsafe {
+            assert_eq!(a.alloc_layout(0, 8) as uint, ZERO_SIZE_PTR as uint);
+            a.dealloc_layout(ZERO_SIZE_PTR, 0, 8);
+        }
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    static mut HOOK_ALLOCS: uint = 0;
+    static mut HOOK_BYTES: uint = 0;
+    static mut HOOK_FREES: uint = 0;
+
+    fn count_alloc(_: *mut u8, size: uint) {
+        unsafe {
+            HOOK_ALLOCS += 1;
+            HOOK_BYTES += size;
+        }
+    }
+
+    fn count_free(_: *mut u8) {
+        unsafe { HOOK_FREES += 1; }
+    }
+
+    #[test]
+    fn hooks_see_each_alloc_and_free() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        a.parent.set_on_alloc(Some(count_alloc));
+        a.parent.set_on_free(Some(count_free));
+        let (p, _) = a.alloc(10);
+        let (q, _) = a.alloc(100);
+        // Neither a failure nor a resize in place is an allocation
+        a.alloc(4096);
+        a.realloc(q, 50);
+        a.free(p);
+        a.free(q);
+        unsafe {
+            assert_eq!(HOOK_ALLOCS, 2);
+            assert_eq!(HOOK_BYTES, 16 + 128);
+            assert_eq!(HOOK_FREES, 2);
+        }
+    }
+
+    #[test]
+    fn event_log_keeps_the_newest_events() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut log = [AllocEvent { seq: 0, op: Allocated, ptr: 0 as *mut u8, size: 0 }, ..4];
+        unsafe { a.parent.record_events(log.as_mut_ptr(), 4); }
+        let (p, _) = a.alloc(16);
+        let (q, _) = a.alloc(32);
+        let (r, _) = a.alloc(64);
+        a.free(p);
+        a.free(q);
+        a.free(r);
+
+        // Six events, so the first two were overwritten
+        let expected = [(r, 64, true), (p, 16, false), (q, 32, false), (r, 64, false)];
+        let mut count = 0;
+        for event in a.parent.events() {
+            let (ptr, size, allocated) = expected[count];
+            assert_eq!(event.seq, count + 2);
+            assert_eq!(event.ptr as uint, ptr as uint);
+            assert_eq!(event.size, size);
+            match event.op {
+                Allocated => assert!(allocated),
+                Freed => assert!(!allocated)
+            }
+            count += 1;
+        }
+        assert_eq!(count, 4);
+    }
+
+    // Where each object of `compact_moves_objects_down` lives now
+    static mut OBJECTS: [uint, ..4] = [0, ..4];
+
+    fn relocate_object(old: *mut u8, new: *mut u8, _: uint) {
+        unsafe {
+            for i in range(0u, 4) {
+                if OBJECTS[i] == old as uint {
+                    OBJECTS[i] = new as uint;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compact_moves_objects_down() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let mut blocks = [0 as *mut u8, ..8];
+        for i in range(0u, 8) {
+            let (p, _) = a.alloc(64);
+            blocks[i] = p;
+        }
+        // Every other block stays, holding its index
+        for i in range(0u, 8) {
+            if i % 2 == 0 {
+                a.free(blocks[i]);
+            }
+            else {
+                fill(blocks[i], i as u8, 64);
+                unsafe { OBJECTS[i / 2] = blocks[i] as uint; }
+            }
+        }
+        // None of the freed blocks can coalesce
+        assert_eq!(a.largest_free_block(), 512);
+
+        assert_eq!(a.compact(relocate_object), 4);
+        assert_eq!(a.used_bytes(), 256);
+        for i in range(0u, 4) {
+            let p = unsafe { OBJECTS[i] } as *mut u8;
+            assert_eq!(a.usable_size(p), 64);
+            for j in range(0u, 64) {
+                assert_eq!(peek(p, j), (i * 2 + 1) as u8);
+            }
+        }
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    fn relocate_nothing(_: *mut u8, _: *mut u8, _: uint) {}
+
+    #[test]
+    fn compact_moves_to_the_lowest_free_block_packing_right() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let mut blocks = [0 as *mut u8, ..4];
+        for i in range(0u, 4) {
+            let (p, _) = a.alloc(64);
+            blocks[i] = p;
+        }
+        a.free(blocks[0]);
+        a.free(blocks[2]);
+        fill(blocks[1], 1, 64);
+        fill(blocks[3], 3, 64);
+        // The policy would hand out the free block at 128 before the one
+        // at 0, but compaction still fills from the bottom.
+        a.parent.set_split_policy(RightFirst);
+        assert_eq!(a.compact(relocate_nothing), 2);
+        assert_eq!(a.usable_size(base as *mut u8), 64);
+        assert_eq!(peek(base as *mut u8, 0), 1);
+        assert_eq!(a.usable_size((base + 64) as *mut u8), 64);
+        assert_eq!(peek((base + 64) as *mut u8, 0), 3);
+        assert_eq!(a.used_bytes(), 128);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn free_lists_follow_every_tree_update() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(100);
+        a.set_free_lists(true);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+
+        let (q, _) = a.alloc(20);
+        assert!(a.reserve_range((base as uint + 512) as *mut u8, 96));
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+        assert_eq!(a.shrink(p, 100, 10), 16);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+        assert_eq!(a.grow(q, 20, 60), Some((q, 64)));
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+        assert!(a.alloc_at((base as uint + 256) as *mut u8, 8).is_some());
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+        a.free(q);
+        a.free(p);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+        a.reset();
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+
+        // Turning them off clears the links the lone free blocks held.
+        a.set_free_lists(false);
+        assert_eq!(peek(base, 0), 0);
+    }
+
+    #[test]
+    fn check_invariants_reports_a_stale_free_list() {
+        let mut arena = Arena::bytes(6);
+        let a = &mut arena.alloc;
+        a.set_free_lists(true);
+        a.alloc(8);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+
+        // Drop the free 16-byte block off its list. Links are two words,
+        // so 8-byte blocks aren't listed on a 64-bit host.
+        a.parent.free_heads[4] = NIL;
+        assert_eq!(a.parent.check_invariants(), Err("unused block missing from its free list"));
+    }
+
+    #[test]
+    fn free_lists_hand_back_the_last_freed_block() {
+        // Every 16-byte block of the arena, as in a steady stream of
+        // same-sized requests; none of them searches the tree.
+        let mut arena = Arena::bytes(12);
+        let a = &mut arena.alloc;
+        a.set_free_lists(true);
+        let mut blocks = [0 as *mut u8, ..256];
+        for i in range(0u, 256) {
+            let (p, size) = a.alloc(16);
+            assert_eq!(size, 16);
+            blocks[i] = p;
+        }
+        for round in range(0u, 8) {
+            for i in range(0u, 128) {
+                a.free(blocks[i * 2 + round % 2]);
+            }
+            assert_eq!(a.parent.check_invariants(), Ok(()));
+            for i in range(0u, 128) {
+                // Last in, first out
+                let (p, _) = a.alloc(16);
+                assert_eq!(p, blocks[(127 - i) * 2 + round % 2]);
+            }
+        }
+        assert_eq!(a.used_bytes(), 4096);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn free_levels_cover_every_unused_node() {
+        let (mut b, _tree) = buddy(8);
+        let mut rng = Rng::new(75);
+        let mut live = [0u, ..32];
+        let mut count = 0;
+        for _ in range(0u, 2000) {
+            if count < 32 && rng.below(2) == 0 {
+                match b.alloc(rng.below(20) + 1) {
+                    Some((offset, _)) => {
+                        live[count] = offset;
+                        count += 1;
+                    }
+                    None => {}
+                }
+            }
+            else if count > 0 {
+                let i = rng.below(count);
+                b.free(live[i]);
+                count -= 1;
+                live[i] = live[count];
+            }
+            // The same walk `check_invariants` makes
+            let counts = b.free_counts();
+            for level in range(0u, 9) {
+                if counts[level] != 0 {
+                    assert!(b.free_levels & (1 << level) != 0);
+                }
+            }
+        }
+
+        // Once the tree is full, a failed search leaves no level to try.
+        while b.alloc(1).is_some() {}
+        assert_eq!(b.free_levels & ((2 << 8) - 1), 0);
+        assert_eq!(b.alloc(1), None);
+    }
+
+    #[test]
+    fn alloc_below_stays_under_the_ceiling() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        let ceiling = (base as uint + 512) as *mut u8;
+        // Take the low end first, so a plain allocation would go above
+        let (low, _) = a.alloc(256);
+        let mut blocks = 0;
+        loop {
+            match a.alloc_below(40, ceiling) {
+                Some((p, size)) => {
+                    assert_eq!(size, 64);
+                    assert!(p as uint + size <= ceiling as uint);
+                    blocks += 1;
+                }
+                None => break
+            }
+        }
+        assert_eq!(blocks, 4);
+        // The upper half is still free
+        assert_eq!(a.largest_free_block(), 512);
+        assert!(a.alloc_below(1, base).is_none());
+        a.free(low);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn owns_covers_exactly_the_arena() {
+        let arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &arena.alloc;
+        assert!(a.owns(base as *mut u8));
+        assert!(a.owns((base + 1023) as *mut u8));
+        assert!(!a.owns((base + 1024) as *mut u8));
+        assert!(!a.owns((base - 1) as *mut u8));
+        assert!(!a.owns(0 as *mut u8));
+        assert!(!a.owns((base + (1 << 20)) as *mut u8));
+    }
+
+    #[test]
+    fn capacity_follows_the_order() {
+        let (b, _tree) = buddy(5);
+        assert_eq!(b.capacity(), 32);
+        let arena = Arena::new(6, 4);
+        // Scaled by the 16-byte leaves
+        assert_eq!(arena.alloc.capacity(), 1 << 10);
+        assert_eq!(arena.alloc.free_bytes(), arena.alloc.capacity());
+    }
+
+    #[test]
+    fn usable_size_is_the_whole_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(100);
+        assert_eq!(a.usable_size(p), 128);
+        let (q, _) = a.alloc(3);
+        assert_eq!(a.usable_size(q), 4);
+        a.free(p);
+        assert_eq!(a.usable_size(p), 0);
+        assert_eq!(a.usable_size(0 as *mut u8), 0);
+        a.free(q);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size_at_every_order() {
+        for order in range(0u, 13) {
+            // The arena's base is aligned to its size, so offsets carry over.
+            let mut arena = Arena::bytes(order);
+            let a = &mut arena.alloc;
+            // One block of each size below the arena's fills all but a leaf.
+            for level in range(0, order) {
+                // Just over half a block, so it rounds up to the whole block
+                let (p, size) = a.alloc((1u << level) / 2 + 1);
+                assert_eq!(size, 1 << level);
+                assert_eq!(p as uint & (size - 1), 0);
+            }
+            assert_eq!(a.used_bytes(), (1 << order) - 1);
+            assert_eq!(a.parent.check_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn alloc_result_names_the_block() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let AllocResult { ptr, size, align } = unsafe { a.alloc_result(100, 1) }.unwrap();
+        assert_eq!((ptr as uint, size), (base, 128));
+        // The base is aligned to the arena's size, and maybe more
+        assert!(align >= 1024 && ptr as uint & (align - 1) == 0);
+        let r = unsafe { a.alloc_result(24, 8) }.unwrap();
+        assert_eq!(r.size, 32);
+        assert_eq!(r.ptr as uint, base + 128);
+        assert_eq!(r.align, 128);
+        // Out of memory is `None` rather than a zero size
+        assert!(unsafe { a.alloc_result(1024, 1) }.is_none());
+        a.free(ptr);
+        a.free(r.ptr);
+    }
+
+    // Eight-byte aligned on the host, more than the base below is
+    struct Aligned {
+        wide: u64,
+        narrow: u32
+    }
+
+    #[test]
+    fn alloc_one_aligns_off_an_unaligned_base() {
+        let memory = Region::new((1 << 8) + 4, 8);
+        let (bitv, tree) = Bitv::on_host(8);
+        let base = (memory.start as uint + 4) as *mut u8;
+        let mut a = Alloc::new(base, 8, 0, bitv, tree.len() * 4);
+        let align = min_align_of::<Aligned>();
+        for i in range(0u, 4) {
+            let p = a.alloc_one::<Aligned>().unwrap();
+            assert_eq!(p as uint & (align - 1), 0);
+            // Uninitialized storage, written in place
+            unsafe { *p = Aligned { wide: i as u64, narrow: i as u32 }; }
+        }
+        let p = a.alloc_one::<Aligned>().unwrap();
+        a.free_one(p);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn alloc_slice_aligns_and_rounds_up_the_capacity() {
+        let memory = Region::new((1 << 8) + 1, 2);
+        let (bitv, tree) = Bitv::on_host(8);
+        let base = (memory.start as uint + 1) as *mut u8;
+        let mut a = Alloc::new(base, 8, 0, bitv, tree.len() * 4);
+        let (p, capacity) = a.alloc_slice::<u16>(5).unwrap();
+        assert_eq!(p as uint & 1, 0);
+        assert!(capacity >= 5);
+        // The 16-byte block, less the byte skipped to align it
+        assert_eq!(capacity, 7);
+        unsafe { *mut_offset(p, 6) = 0xffff; }
+        assert!(a.alloc_slice::<u16>(!0u / 2 + 1).is_none());
+        a.free(p as *mut u8);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn grow_extends_in_place_or_moves() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(32);
+        fill(p, 1, 32);
+        // Its buddies up to 128 bytes are free
+        assert_eq!(a.grow(p, 32, 100), Some((p, 128)));
+        assert_eq!(a.used_bytes(), 128);
+
+        // Now the buddy is taken, so growing has to move
+        let (q, _) = a.alloc(128);
+        let (moved, size) = a.grow(p, 32, 200).unwrap();
+        assert!(moved != p);
+        assert_eq!(size, 256);
+        assert!(holds(moved, 1, 32));
+        assert_eq!(a.usable_size(p), 0);
+        assert_eq!(a.used_bytes(), 384);
+
+        // Shrinking never moves, and needs `old_size` to fit the block
+        assert_eq!(a.shrink(moved, 300, 20), 0);
+        assert_eq!(a.used_bytes(), 384);
+        assert_eq!(a.shrink(moved, 200, 20), 32);
+        assert_eq!(a.used_bytes(), 160);
+        assert!(holds(moved, 1, 20));
+        a.free(moved);
+        a.free(q);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn nothing_is_handed_out_past_the_usable_end() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        // Memory stops 100 bytes into the last 256-byte block
+        let end = base + 868;
+        assert!(a.set_usable_end(end as *mut u8));
+        let (p, _) = a.alloc(512);
+        assert_eq!(p as uint, base);
+        let (q, _) = a.alloc(256);
+        assert_eq!(q as uint, base + 512);
+        // The last block would cross the end
+        let (_, size) = a.alloc(256);
+        assert_eq!(size, 0);
+        let mut top = 0;
+        loop {
+            match a.alloc(4) {
+                (_, 0) => break,
+                (p, size) => {
+                    if p as uint + size > top {
+                        top = p as uint + size;
+                    }
+                }
+            }
+        }
+        assert_eq!(top, end);
+        assert_eq!(a.used_bytes(), 1024);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn heap_init_bounds_checks_its_tree() {
+        let memory = Region::new(1 << 14, 4096);
+        let end = (memory.start as uint + (1 << 14)) as *mut u8;
+        let mut a = unsafe { heap_init(memory.start, end) };
+        // The tree is sized for the chosen order, so stray indices abort
+        assert_eq!(a.parent.tree.nodes, Bitv::nodes_for_order(a.order()));
+        assert!(a.base as uint + (1 << a.order()) <= end as uint);
+        let (p, size) = a.alloc(100);
+        assert_eq!(size, 128);
+        a.free(p);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn single_unit_alloc_finds_a_lone_free_leaf() {
+        let (mut b, _tree) = buddy(6);
+        for i in range(0u, 64) {
+            assert_eq!(b.alloc(1), Some((i, 1)));
+        }
+        b.free(37);
+        assert_eq!(b.alloc(1), Some((37, 1)));
+
+        // The leaves of a coalesced half are stale and skipped, leaving
+        // the one free leaf past them
+        for i in range(0u, 32) {
+            b.free(i);
+        }
+        b.free(40);
+        assert_eq!(b.alloc(1), Some((40, 1)));
+        assert_eq!(b.largest_free(), 32);
+        assert_eq!(b.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn split_policy_picks_the_end_allocations_pack_toward() {
+        let (mut left, _left_tree) = buddy(6);
+        let (mut right, _right_tree) = buddy(6);
+        right.set_split_policy(RightFirst);
+        for &size in [1u, 4, 8, 2].iter() {
+            let (l, _) = left.alloc(size).unwrap();
+            let (r, got) = right.alloc(size).unwrap();
+            // Mirror images of each other
+            assert_eq!(r, 64 - got - l);
+        }
+        assert_eq!(left.alloc(1), Some((1, 1)));
+        assert_eq!(right.alloc(1), Some((62, 1)));
+        // The other end stays free in one block either way
+        assert_eq!(left.largest_free(), 32);
+        assert_eq!(right.largest_free(), 32);
+        assert_eq!(right.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn too_large_is_told_apart_from_out_of_memory() {
+        let mut arena = Arena::bytes(8);
+        let a = &mut arena.alloc;
+        match a.try_alloc(257) {
+            Err(SizeTooLarge) => {}
+            _ => fail!("expected SizeTooLarge")
+        }
+        let (p, _) = a.alloc(256);
+        // It would fit once `p` is freed
+        match a.try_alloc(256) {
+            Err(OutOfMemory) => {}
+            _ => fail!("expected OutOfMemory")
+        }
+        a.free(p);
+        assert!(a.try_alloc(256).is_ok());
+
+        let (mut b, _tree) = buddy(4);
+        match b.try_alloc_aligned(1, 32) {
+            Err(SizeTooLarge) => {}
+            _ => fail!("expected SizeTooLarge")
+        }
+    }
+
+    #[test]
+    fn free_range_coalesces_a_contiguous_run() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        let (first, _) = a.alloc(16);
+        for _ in range(0u, 31) {
+            a.alloc(16);
+        }
+        // Something past the run, which stays
+        let (after, _) = a.alloc(64);
+        assert_eq!(first, base);
+        assert_eq!(a.free_range(first, 512), 32);
+        assert_eq!(a.used_bytes(), 64);
+        assert_eq!(a.largest_free_block(), 512);
+        assert_eq!(a.usable_size(after), 64);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn order_matches_the_constructor() {
+        let (b, _tree) = buddy(7);
+        assert_eq!(b.order(), 7);
+        let arena = Arena::new(5, 12);
+        assert_eq!(arena.alloc.order(), 5);
+        assert_eq!(arena.alloc.min_block_size(), 4096);
+        // The largest block, from the two together
+        assert_eq!(arena.alloc.min_block_size() << arena.alloc.order(),
+                   arena.alloc.capacity());
+    }
+
+    #[test]
+    fn size_histogram_counts_blocks_by_level() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        a.alloc(1);
+        a.alloc(3);
+        a.alloc(4);
+        let (p, _) = a.alloc(100);
+        a.alloc(100);
+        // Reserved space isn't an allocation
+        a.reserve_range((base as uint + 512) as *mut u8, 256);
+        let counts = a.parent.size_histogram();
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[2], 2);
+        assert_eq!(counts[7], 2);
+        assert_eq!(counts[8], 0);
+        let total = counts.iter().fold(0u, |n, &c| n + c);
+        assert_eq!(total, 5);
+
+        a.free(p);
+        assert_eq!(a.parent.size_histogram()[7], 1);
+    }
+
+    #[test]
+    fn snapshot_diff_finds_the_new_blocks() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(16);
+        let (q, _) = a.alloc(64);
+        let mut before = [0 as *mut u8, ..8];
+        let n = a.snapshot(&mut before);
+        assert_eq!(n, 2);
+
+        let (r, _) = a.alloc(32);
+        a.free(p);
+        let (s, _) = a.alloc(200);
+        let mut after = [0 as *mut u8, ..8];
+        let m = a.snapshot(&mut after);
+        assert_eq!(m, 3);
+        assert_eq!(after[1], q);
+        let mut added = [0 as *mut u8, ..8];
+        assert_eq!(snapshot_diff(before.slice_to(n), after.slice_to(m), &mut added), 2);
+        // In address order
+        assert_eq!(added[0], r);
+        assert_eq!(added[1], s);
+
+        // Only what fits is written, but all are counted
+        let mut one = [0 as *mut u8, ..1];
+        assert_eq!(snapshot_diff(&[], after.slice_to(m), &mut one), 3);
+        assert_eq!(one[0], r);
+    }
+
+    #[test]
+    fn free_interior_frees_the_whole_block() {
+        let mut arena = Arena::bytes(10);
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(100);
+        let (q, _) = a.alloc(16);
+        let inside = (p as uint + 77) as *mut u8;
+        assert!(a.free_interior(inside).is_ok());
+        assert_eq!(a.usable_size(p), 0);
+        assert_eq!(a.used_bytes(), 16);
+        // The block is gone, so the same pointer now lands in free space
+        match a.free_interior(inside) {
+            Err(DoubleFree) => {}
+            _ => fail!("expected DoubleFree")
+        }
+        assert!(a.free_interior(q).is_ok());
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn realloc_align_keeps_an_over_aligned_block_aligned() {
+        // Off the base, blocks are only 16-byte aligned of their own accord.
+        let memory = Region::new((1 << 15) + 16, 4096);
+        let (bitv, tree) = Bitv::on_host(15);
+        let base = (memory.start as uint + 16) as *mut u8;
+        let mut a = Alloc::new(base, 15, 0, bitv, tree.len() * 4);
+        let (p, _) = unsafe { a.alloc_align(100, 4096) };
+        assert_eq!(p as uint & 4095, 0);
+        fill(p, 9, 100);
+        // Take the buddy, so that growing has to move
+        let (r, _) = a.alloc(1);
+        let (q, size) = unsafe { a.realloc_align(p, 5000, 4096) }.unwrap();
+        assert!(q != p);
+        assert!(size >= 5000);
+        assert_eq!(q as uint & 4095, 0);
+        assert!(holds(q, 9, 100));
+        a.free(q);
+        a.free(r);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn realloc_align_leaves_a_pointer_it_does_not_own_alone() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base() as uint;
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(64);
+        a.free(p);
+        for &src in [p, (base + 512) as *mut u8].iter() {
+            assert!(unsafe { a.realloc_align(src, 100, 64) }.is_none());
+        }
+        assert_eq!(a.used_bytes(), 0);
+        assert_eq!(a.parent.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn zero_alloc_skips_space_never_handed_out() {
+        let mut arena = Arena::bytes(10);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        let (p, _) = a.alloc(64);
+        a.assume_zeroed();
+        // A marker in space declared zero shows whether it gets cleared
+        fill((base as uint + 64) as *mut u8, 0xAA, 1);
+
+        let (q, size) = a.zero_alloc(64);
+        assert_eq!(q as uint, base as uint + 64);
+        assert_eq!(size, 64);
+        assert_eq!(peek(q, 0), 0xAA);
+
+        // A block handed out before is cleared as usual
+        fill(p, 0xBB, 64);
+        a.free(p);
+        let (r, _) = a.zero_alloc(64);
+        assert_eq!(r, p);
+        assert!(holds(r, 0, 64));
+        a.free(q);
+        a.free(r);
+    }
+
+    #[test]
+    fn dirty_map_zeroes_only_written_blocks() {
+        let mut arena = Arena::bytes(8);
+        let base = arena.base();
+        let a = &mut arena.alloc;
+        let mut map = [0u32, ..8];
+        unsafe { a.set_dirty_map(map.as_mut_ptr()); }
+        a.assume_zeroed();
+
+        let (p, _) = a.alloc(32);
+        fill(p, 0xCC, 32);
+        let (q, _) = a.alloc(32);
+        a.free(p);
+        // Freeing marks the block, and only the block
+        assert_eq!(map[0], 0xffffffff);
+        assert_eq!(map[1], 0);
+
+        // A freed block is cleared and unmarked
+        let (r, _) = a.zero_alloc(32);
+        assert_eq!(r, p);
+        assert!(holds(r, 0, 32));
+        assert_eq!(map[0], 0);
+
+        // A clean one is not written: the marker survives
+        fill((base as uint + 128) as *mut u8, 0xAA, 1);
+        a.free(q);
+        assert_eq!(map[1], 0xffffffff);
+        let (s, _) = a.zero_alloc(128);
+        assert_eq!(s as uint, base as uint + 128);
+        assert_eq!(peek(s, 0), 0xAA);
+        assert_eq!(map[4], 0);
+        a.free(r);
+        a.free(s);
+    }
+
+    #[test]
+    fn dirty_map_survives_reset() {
+        let mut arena = Arena::bytes(8);
+        let a = &mut arena.alloc;
+        let mut map = [0u32, ..8];
+        unsafe { a.set_dirty_map(map.as_mut_ptr()); }
+        a.assume_zeroed();
+
+        let (p, _) = a.alloc(32);
+        fill(p, 0xCC, 32);
+        let (q, _) = a.alloc(32);
+        a.reset();
+        // Both blocks are marked: the written one still holds its bytes
+        assert_eq!(map[0], 0xffffffff);
+        assert_eq!(map[1], 0xffffffff);
+        assert_eq!(peek(p, 0), 0xCC);
+
+        let (r, _) = a.zero_alloc(32);
+        assert_eq!(r, p);
+        assert!(holds(r, 0, 32));
+        assert_eq!(map[0], 0);
+        assert_eq!(map[1], 0xffffffff);
+        assert_eq!(a.usable_size(q), 0);
+    }
+
+    // A block held back as a cache that `drop_cache` gives up under pressure
+    static mut CACHED: uint = 0;
+    static mut HANDLER_CALLS: uint = 0;
+
+    fn drop_cache(a: &mut Alloc, _: uint) -> bool {
+        unsafe {
+            HANDLER_CALLS += 1;
+            if CACHED == 0 {
+                return false;
+            }
+            a.free(CACHED as *mut u8);
+            CACHED = 0;
+        }
+        true
+    }
+
+    #[test]
+    fn oom_handler_frees_a_cached_block_for_the_retry() {
+        let mut arena = Arena::bytes(8);
+        let a = &mut arena.alloc;
+        a.set_oom_handler(Some(drop_cache));
+        let (cache, _) = a.alloc(128);
+        unsafe { CACHED = cache as uint; }
+        let (p, _) = a.alloc(128);
+
+        // Only the cache stands in the way
+        let (q, size) = a.alloc(128);
+        assert_eq!(size, 128);
+        assert_eq!(q, cache);
+        assert_eq!(unsafe { HANDLER_CALLS }, 1);
+
+        // Nothing left to reclaim, so this fails after asking once more
+        let (_, size) = a.alloc(1);
+        assert_eq!(size, 0);
+        assert_eq!(unsafe { HANDLER_CALLS }, 2);
+        // Too large for the arena, which no reclaiming helps
+        let (_, size) = a.alloc(512);
+        assert_eq!(size, 0);
+        assert_eq!(unsafe { HANDLER_CALLS }, 2);
+        a.free(p);
+        a.free(q);
     }
 }
@@ -0,0 +1,188 @@
+use core::i32::cttz32;
+use core::ptr::set_memory;
+use core::fail::assert;
+use core::option::{Option, Some, None};
+
+use kernel::mm::Allocator;
+use util::ptr::mut_offset;
+
+/// Hands out blocks of a single size from a bitmap with one bit per
+/// block, about a quarter of the metadata of a `BuddyAlloc` tree for the same
+/// arena. It fits arenas where every allocation is one block, like page
+/// frames; requests for more than a unit fail, since there is no way to
+/// find runs of free blocks quickly.
+///
+/// As with `Alloc`, sizes passed in are in units of `1 << el_size` bytes
+/// and sizes returned are in bytes.
+pub struct BitmapAlloc {
+    priv base: *mut u8,
+    priv el_size: uint,
+    priv count: uint,
+    priv bits: *mut u32,
+    // word to start searching from
+    priv hint: uint,
+    priv used: uint
+}
+
+impl BitmapAlloc {
+    /// Bytes of bitmap an arena of `count` blocks needs
+    pub fn bytes_for(count: uint) -> uint {
+        (count + 31) / 32 * 4
+    }
+
+    /// Manage `count` blocks of `1 << el_size` bytes at `base`, tracked in
+    /// `bits`, which must hold `bytes_for(count)` bytes.
+    pub fn new(base: *mut u8, el_size: uint, count: uint, bits: *mut u32) -> BitmapAlloc {
+        unsafe {
+            set_memory(bits as *mut u8, 0, BitmapAlloc::bytes_for(count));
+            // Blocks past the end of the last word are never handed out
+            if count % 32 != 0 {
+                *mut_offset(bits, (count / 32) as int) = !0u32 << (count % 32);
+            }
+        }
+        BitmapAlloc {
+            base: base,
+            el_size: el_size,
+            count: count,
+            bits: bits,
+            hint: 0,
+            used: 0
+        }
+    }
+
+    /// Blocks currently allocated
+    pub fn used(&self) -> uint {
+        self.used
+    }
+
+    #[inline]
+    fn word(&self, w: uint) -> *mut u32 {
+        unsafe { mut_offset(self.bits, w as int) }
+    }
+
+    // Index of a free block whose address is a multiple of `align`
+    fn find(&self, align: uint) -> Option<uint> {
+        let words = (self.count + 31) / 32;
+        let mut n = 0;
+        while n < words {
+            let w = (self.hint + n) % words;
+            let mut free = unsafe { !*self.word(w) };
+            while free != 0 {
+                let bit = unsafe { cttz32(free as i32) } as uint;
+                let i = w * 32 + bit;
+                let addr = self.base as uint + (i << self.el_size);
+                if addr & (align - 1) == 0 {
+                    return Some(i);
+                }
+                free &= !(1 << bit);
+            }
+            n += 1;
+        }
+        None
+    }
+}
+
+impl Allocator for BitmapAlloc {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        assert(align != 0 && align & (align - 1) == 0);
+        if size > 1 {
+            return (self.base, 0);
+        }
+        match self.find(align) {
+            None => (self.base, 0),
+            Some(i) => {
+                *self.word(i / 32) |= 1 << (i % 32);
+                self.hint = i / 32;
+                self.used += 1;
+                ((self.base as uint + (i << self.el_size)) as *mut u8, 1 << self.el_size)
+            }
+        }
+    }
+
+    // Blocks never change size, so a realloc either fits or fails.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        if size > 1 {
+            return (self.base, 0);
+        }
+        (src, 1 << self.el_size)
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if !self.owns(ptr) {
+            return;
+        }
+        let i = (ptr as uint - self.base as uint) >> self.el_size;
+        unsafe {
+            let w = self.word(i / 32);
+            if *w & (1 << (i % 32)) != 0 {
+                *w &= !(1 << (i % 32));
+                self.used -= 1;
+            }
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.base && (ptr as uint - self.base as uint) >> self.el_size < self.count
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if !self.owns(ptr) {
+            return 0;
+        }
+        let i = (ptr as uint - self.base as uint) >> self.el_size;
+        if unsafe { *self.word(i / 32) } & (1 << (i % 32)) == 0 {
+            return 0;
+        }
+        self.base as uint + ((i + 1) << self.el_size) - ptr as uint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, BitmapAlloc};
+    use kernel::mm::testing::Region;
+
+    #[test]
+    fn bitmap_hands_out_each_block_once() {
+        // Not a whole number of words, so the tail of the last one is unused
+        let count = 40;
+        let memory = Region::new(count << 6, 64);
+        let mut bits = Vec::from_elem(BitmapAlloc::bytes_for(count) / 4, 0u32);
+        let mut b = BitmapAlloc::new(memory.start, 6, count, bits.as_mut_ptr());
+        let mut seen = [false, ..40];
+        for _ in range(0u, count) {
+            let (p, size) = b.alloc(1);
+            assert_eq!(size, 64);
+            assert!(b.owns(p));
+            let i = (p as uint - memory.start as uint) >> 6;
+            assert!(!seen[i]);
+            seen[i] = true;
+        }
+        assert_eq!(b.used(), count);
+        let (_, size) = b.alloc(1);
+        assert_eq!(size, 0);
+
+        let p = (memory.start as uint + (17 << 6)) as *mut u8;
+        b.free(p);
+        assert_eq!(b.used(), count - 1);
+        let (q, _) = b.alloc(1);
+        assert_eq!(q as uint, p as uint);
+    }
+
+    #[test]
+    fn bitmap_rejects_more_than_a_block() {
+        let memory = Region::new(32 << 6, 64);
+        let mut bits = Vec::from_elem(BitmapAlloc::bytes_for(32) / 4, 0u32);
+        let mut b = BitmapAlloc::new(memory.start, 6, 32, bits.as_mut_ptr());
+        let (_, size) = b.alloc(2);
+        assert_eq!(size, 0);
+        assert_eq!(b.used(), 0);
+    }
+}
@@ -0,0 +1,70 @@
+use core::ops::Drop;
+use core::option::{Option, Some, None};
+
+use kernel::mm::Allocator;
+
+/// A block from `A` that is freed when the box goes out of scope.
+///
+/// The box borrows its allocator mutably for its whole life, so while it
+/// is alive the allocator can't be used for anything else, and the borrow
+/// checker keeps the box from outliving it. That suits a scratch buffer
+/// in one function, such as from an allocator borrowed inside
+/// `LockedAllocator::with`. Blocks that must live side by side in one
+/// allocator are still managed by hand.
+pub struct AllocBox<'a, A> {
+    priv alloc: &'a mut A,
+    priv ptr: *mut u8,
+    priv size: uint
+}
+
+impl<'a, A: Allocator> AllocBox<'a, A> {
+    /// Allocate `size` from `alloc`, or `None` when it is out of memory.
+    pub fn new(alloc: &'a mut A, size: uint) -> Option<AllocBox<'a, A>> {
+        match alloc.alloc(size) {
+            (_, 0) if size != 0 => None,
+            (ptr, sz) => Some(AllocBox { alloc: alloc, ptr: ptr, size: sz })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *u8 {
+        self.ptr as *u8
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Usable bytes, which may be more than were asked for
+    pub fn len(&self) -> uint {
+        self.size
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, A: Allocator> Drop for AllocBox<'a, A> {
+    fn drop(&mut self) {
+        self.alloc.free(self.ptr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::AllocBox;
+    use kernel::mm::testing::{Arena, fill};
+
+    #[test]
+    fn dropping_a_box_frees_its_block() {
+        let mut arena = Arena::bytes(8);
+        {
+            let mut b = AllocBox::new(&mut arena.alloc, 100).unwrap();
+            assert_eq!(b.len(), 128);
+            fill(b.as_mut_ptr(), 7, 128);
+        }
+        assert_eq!(arena.alloc.used_bytes(), 0);
+        assert_eq!(arena.alloc.largest_free_block(), 256);
+
+        // Too large for the arena
+        assert!(AllocBox::new(&mut arena.alloc, 512).is_none());
+        assert_eq!(arena.alloc.used_bytes(), 0);
+    }
+}
@@ -0,0 +1,103 @@
+use core::ptr::copy_memory;
+use core::fail::assert;
+
+use kernel::mm::Allocator;
+
+/// Hands out memory by advancing a pointer through `[base, end)` and never
+/// reclaims it. Meant for early boot, before a buddy allocator's tree has
+/// storage.
+pub struct BumpAllocator {
+    base: *mut u8,
+    next: *mut u8,
+    end: *mut u8
+}
+
+impl BumpAllocator {
+    pub fn new(base: *mut u8, end: *mut u8) -> BumpAllocator {
+        BumpAllocator { base: base, next: base, end: end }
+    }
+}
+
+impl Allocator for BumpAllocator {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        assert(align != 0 && align & (align - 1) == 0);
+        let next = self.next as uint;
+        let start = (next + align - 1) & !(align - 1);
+        if start < next || start > self.end as uint || size > self.end as uint - start {
+            return (self.base, 0);
+        }
+        self.next = (start + size) as *mut u8;
+        (start as *mut u8, size)
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if size == 0 {
+            return (0 as *mut u8, 0);
+        }
+        // Sizes aren't recorded, so copy at most up to the old `next`.
+        let old_next = self.next as uint;
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                if src >= self.base && (src as uint) < old_next {
+                    let avail = old_next - src as uint;
+                    unsafe {
+                        copy_memory(ptr, src as *u8, if avail < sz { avail } else { sz });
+                    }
+                }
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, _: *mut u8) {
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.base && ptr < self.end
+    }
+
+    // Sizes aren't recorded; everything below `next` has been handed out.
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if ptr >= self.base && ptr < self.next {
+            self.next as uint - ptr as uint
+        }
+        else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, BumpAllocator};
+    use kernel::mm::testing::Region;
+
+    #[test]
+    fn bump_alloc_aligns_each_block() {
+        let memory = Region::new(256, 64);
+        let start = memory.start as uint;
+        let mut b = BumpAllocator::new(memory.start, memory.end());
+        let (p, _) = b.alloc(3);
+        assert_eq!(p as uint, start);
+        let (q, size) = unsafe { b.alloc_align(8, 16) };
+        assert_eq!(q as uint, start + 16);
+        assert_eq!(size, 8);
+        let (r, _) = unsafe { b.alloc_align(1, 64) };
+        assert_eq!(r as uint, start + 64);
+    }
+
+    #[test]
+    fn bump_alloc_runs_out_at_the_end() {
+        let memory = Region::new(64, 16);
+        let mut b = BumpAllocator::new(memory.start, memory.end());
+        let (_, size) = b.alloc(60);
+        assert_eq!(size, 60);
+        assert_eq!(b.alloc(8), (memory.start, 0));
+        // What is left can still be used, exactly
+        let (_, size) = b.alloc(4);
+        assert_eq!(size, 4);
+        assert!(b.try_alloc(1).is_err());
+        assert!(unsafe { b.alloc_result(1, 1) }.is_none());
+    }
+}
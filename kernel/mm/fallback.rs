@@ -0,0 +1,95 @@
+use kernel::mm::Allocator;
+
+/// Serves allocations from `primary` until it runs out, then from
+/// `secondary`. Frees go to whichever of the two owns the pointer.
+pub struct FallbackAllocator<A, B> {
+    priv primary: A,
+    priv secondary: B
+}
+
+impl<A: Allocator, B: Allocator> FallbackAllocator<A, B> {
+    pub fn new(primary: A, secondary: B) -> FallbackAllocator<A, B> {
+        FallbackAllocator { primary: primary, secondary: secondary }
+    }
+
+    pub fn primary<'a>(&'a mut self) -> &'a mut A {
+        &mut self.primary
+    }
+
+    pub fn secondary<'a>(&'a mut self) -> &'a mut B {
+        &mut self.secondary
+    }
+}
+
+impl<A: Allocator, B: Allocator> Allocator for FallbackAllocator<A, B> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        match self.primary.alloc_align(size, align) {
+            (_, 0) => self.secondary.alloc_align(size, align),
+            r => r
+        }
+    }
+
+    // Block sizes aren't known here, so a block stays with the allocator
+    // that owns it; if that allocator can't resize it, realloc fails.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if self.primary.owns(src) {
+            self.primary.realloc(src, size)
+        }
+        else {
+            self.secondary.realloc(src, size)
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if self.primary.owns(ptr) {
+            self.primary.free(ptr);
+        }
+        else if self.secondary.owns(ptr) {
+            self.secondary.free(ptr);
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.primary.owns(ptr) || self.secondary.owns(ptr)
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.primary.owns(ptr) {
+            self.primary.usable_size(ptr)
+        }
+        else {
+            self.secondary.usable_size(ptr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, FallbackAllocator};
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn fallback_overflows_to_the_secondary() {
+        let small = Arena::bytes(6);
+        let big = Arena::bytes(10);
+        let mut f = FallbackAllocator::new(small.alloc, big.alloc);
+        let (p, _) = f.alloc(64);
+        assert!(f.primary().owns(p));
+        // The primary is full, so this comes from the secondary
+        let (q, size) = f.alloc(16);
+        assert_eq!(size, 16);
+        assert!(f.secondary().owns(q));
+        assert!(!f.primary().owns(q));
+
+        f.free(q);
+        assert_eq!(f.secondary().used_bytes(), 0);
+        f.free(p);
+        assert_eq!(f.primary().used_bytes(), 0);
+        // Once the primary has room again it is used first
+        let (r, _) = f.alloc(16);
+        assert!(f.primary().owns(r));
+    }
+}
@@ -0,0 +1,104 @@
+use core::option::{Option, Some, None};
+use core::fail::assert;
+
+use cpu::mmu::{PAGE_SIZE, PAGE_SIZE_LOG2};
+use kernel::mm::{Allocator, Alloc};
+use util::bitv::Bitv;
+
+/// Page frames handed out by a buddy tree whose leaves are pages, so the
+/// tree tracks frames rather than bytes. Frame runs are rounded up to a
+/// power of two and aligned to their size relative to `base`.
+pub struct FrameAllocator {
+    alloc: Alloc
+}
+
+impl FrameAllocator {
+    /// Manage `1 << order` frames from the page-aligned `base`, with a tree
+    /// in `storage` of `storage_bytes` bytes.
+    pub fn new(base: *mut u8, order: uint, storage: Bitv, storage_bytes: uint) -> FrameAllocator {
+        assert(base as uint & (PAGE_SIZE - 1) == 0);
+        FrameAllocator {
+            alloc: Alloc::new(base, order, PAGE_SIZE_LOG2, storage, storage_bytes)
+        }
+    }
+
+    pub fn alloc_frame(&mut self) -> Option<*mut u8> {
+        match self.alloc_frames(1) {
+            Some((ptr, _)) => Some(ptr),
+            None => None
+        }
+    }
+
+    /// Allocate `count` contiguous frames, returning the first and the
+    /// number of frames actually reserved.
+    pub fn alloc_frames(&mut self, count: uint) -> Option<(*mut u8, uint)> {
+        if count == 0 {
+            return None;
+        }
+        match unsafe { self.alloc.alloc_opt(count, 1) } {
+            Some((ptr, size)) => Some((ptr, size >> PAGE_SIZE_LOG2)),
+            None => None
+        }
+    }
+
+    /// `alloc_frames` with the frames zeroed. Frames never handed out
+    /// since `BuddyAlloc::assume_zeroed` are known to be zero already.
+    pub fn zero_alloc_frames(&mut self, count: uint) -> Option<(*mut u8, uint)> {
+        if count == 0 {
+            return None;
+        }
+        match unsafe { self.alloc.zero_alloc_opt(count, 1) } {
+            Some((ptr, size)) => Some((ptr, size >> PAGE_SIZE_LOG2)),
+            None => None
+        }
+    }
+
+    /// Free a run of frames from its first frame.
+    pub fn free_frames(&mut self, ptr: *mut u8) {
+        self.alloc.free(ptr);
+    }
+
+    pub fn free_count(&self) -> uint {
+        self.alloc.free_bytes() >> PAGE_SIZE_LOG2
+    }
+
+    pub fn used_count(&self) -> uint {
+        self.alloc.used_bytes() >> PAGE_SIZE_LOG2
+    }
+
+    /// The underlying allocator, for reservations and diagnostics
+    pub fn inner<'a>(&'a mut self) -> &'a mut Alloc {
+        &mut self.alloc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::mmu::PAGE_SIZE;
+    use kernel::mm::FrameAllocator;
+    use kernel::mm::testing::Region;
+    use util::bitv::Bitv;
+
+    #[test]
+    fn frame_runs_are_contiguous_and_page_aligned() {
+        let memory = Region::new(16 * PAGE_SIZE, 16 * PAGE_SIZE);
+        let (bitv, tree) = Bitv::on_host(4);
+        let mut f = FrameAllocator::new(memory.start, 4, bitv, tree.len() * 4);
+        let (p, count) = f.alloc_frames(3).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!((p as uint - memory.start as uint) % (4 * PAGE_SIZE), 0);
+        let q = f.alloc_frame().unwrap();
+        assert_eq!(q as uint & (PAGE_SIZE - 1), 0);
+        assert!(q as uint >= p as uint + 4 * PAGE_SIZE || (q as uint) < p as uint);
+        assert_eq!(f.used_count(), 5);
+        assert_eq!(f.free_count(), 11);
+
+        f.free_frames(p);
+        assert_eq!(f.used_count(), 1);
+        // One frame still splits the arena
+        assert!(f.alloc_frames(16).is_none());
+        f.free_frames(q);
+        assert_eq!(f.alloc_frames(16), Some((memory.start, 16)));
+        assert!(f.alloc_frames(0).is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use core::mem::size_of;
+use core::ptr::copy_memory;
+use core::fail::assert;
+
+use kernel::mm::{Allocator, zero_size_ptr, is_zero_size};
+
+// Header in front of every chunk. `size` includes the header; `next` links
+// free chunks in address order and is unused while the chunk is allocated.
+struct Chunk {
+    size: uint,
+    next: *mut Chunk
+}
+
+/// First-fit allocator keeping free chunks in an address-ordered list
+/// threaded through the chunks themselves. Requests are only rounded to
+/// the header size, and adjacent free chunks are merged on `free`.
+pub struct FreeListAllocator {
+    priv base: *mut u8,
+    priv end: *mut u8,
+    priv head: *mut Chunk
+}
+
+#[inline]
+fn header() -> uint {
+    size_of::<Chunk>()
+}
+
+#[inline]
+fn round_up(x: uint, align: uint) -> uint {
+    (x + align - 1) & !(align - 1)
+}
+
+impl FreeListAllocator {
+    pub unsafe fn new(base: *mut u8, size: uint) -> FreeListAllocator {
+        let start = round_up(base as uint, header());
+        let end = (base as uint + size) & !(header() - 1);
+        let mut this = FreeListAllocator {
+            base: start as *mut u8,
+            end: end as *mut u8,
+            head: 0 as *mut Chunk
+        };
+        if end >= start + header() {
+            let chunk = start as *mut Chunk;
+            (*chunk).size = end - start;
+            (*chunk).next = 0 as *mut Chunk;
+            this.head = chunk;
+        }
+        this
+    }
+
+    // Usable bytes of the allocated chunk at `ptr`
+    unsafe fn size_of(&self, ptr: *mut u8) -> uint {
+        (*((ptr as uint - header()) as *mut Chunk)).size - header()
+    }
+}
+
+impl Allocator for FreeListAllocator {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        assert(align != 0 && align & (align - 1) == 0);
+        // As with `Alloc`, nothing is taken for a zero-sized request
+        if size == 0 {
+            return (zero_size_ptr(align), 0);
+        }
+        // Chunks start on header-size boundaries, so any gap left in front
+        // of an aligned payload is big enough to stay a free chunk.
+        let align = if align < header() { header() } else { align };
+        // No chunk could hold a size this close to the top of the address
+        // space, and rounding it would wrap.
+        if size > !0u - header() {
+            return (self.base, 0);
+        }
+        let need = round_up(size, header());
+
+        let mut prev = 0 as *mut Chunk;
+        let mut cur = self.head;
+        while cur as uint != 0 {
+            let start = cur as uint;
+            let end = start + (*cur).size;
+            let payload = round_up(start + header(), align);
+
+            // Compared as distances, as `payload + need` could wrap
+            if payload >= start && payload <= end && need <= end - payload {
+                let block = payload - header();
+                let next = (*cur).next;
+
+                // Keep the gap in front as a free chunk
+                let mut link = prev;
+                if block > start {
+                    (*cur).size = block - start;
+                    link = cur;
+                }
+
+                // Split off the tail if it can hold a header
+                let tail = payload + need;
+                let mut after = next;
+                let mut used = need;
+                if end - tail >= header() {
+                    let rest = tail as *mut Chunk;
+                    (*rest).size = end - tail;
+                    (*rest).next = next;
+                    after = rest;
+                }
+                else {
+                    used = end - payload;
+                }
+
+                if link as uint == 0 {
+                    self.head = after;
+                }
+                else {
+                    (*link).next = after;
+                }
+                (*(block as *mut Chunk)).size = header() + used;
+                return (payload as *mut u8, used);
+            }
+
+            prev = cur;
+            cur = (*cur).next;
+        }
+        (self.base, 0)
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 || is_zero_size(src) {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        let old_size = unsafe { self.size_of(src) };
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                unsafe {
+                    copy_memory(ptr, src as *u8, if old_size < sz { old_size } else { sz });
+                }
+                self.free(src);
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if is_zero_size(ptr) || (ptr as uint) < self.base as uint + header() || ptr >= self.end {
+            return;
+        }
+        unsafe {
+            let chunk = (ptr as uint - header()) as *mut Chunk;
+
+            // Find the free neighbors on either side
+            let mut prev = 0 as *mut Chunk;
+            let mut next = self.head;
+            while next as uint != 0 && (next as uint) < chunk as uint {
+                prev = next;
+                next = (*next).next;
+            }
+
+            (*chunk).next = next;
+            if next as uint != 0 && chunk as uint + (*chunk).size == next as uint {
+                (*chunk).size += (*next).size;
+                (*chunk).next = (*next).next;
+            }
+
+            if prev as uint == 0 {
+                self.head = chunk;
+            }
+            else if prev as uint + (*prev).size == chunk as uint {
+                (*prev).size += (*chunk).size;
+                (*prev).next = (*chunk).next;
+            }
+            else {
+                (*prev).next = chunk;
+            }
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.base && ptr < self.end
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.owns(ptr) { unsafe { self.size_of(ptr) } } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, FreeListAllocator, ZERO_SIZE_PTR};
+    use kernel::mm::freelist::header;
+    use kernel::mm::testing::Region;
+
+    #[test]
+    fn free_list_splits_a_chunk() {
+        let memory = Region::new(1024, 16);
+        let mut f = unsafe { FreeListAllocator::new(memory.start, 1024) };
+        let (p, p_size) = f.alloc(32);
+        let (q, _) = f.alloc(20);
+        assert_eq!(p as uint, memory.start as uint + header());
+        assert_eq!(p_size, 32);
+        // The rest of the chunk was split off and holds the next block
+        assert_eq!(q as uint, p as uint + 32 + header());
+        assert_eq!(f.usable_size(q), (20 + header() - 1) & !(header() - 1));
+    }
+
+    #[test]
+    fn free_list_coalesces_adjacent_frees() {
+        let memory = Region::new(1024, 16);
+        let mut f = unsafe { FreeListAllocator::new(memory.start, 1024) };
+        let (p, _) = f.alloc(32);
+        let (q, _) = f.alloc(32);
+        let (r, _) = f.alloc(32);
+        // Freed out of order, the three merge with each other and the rest
+        f.free(q);
+        f.free(r);
+        f.free(p);
+        let (all, size) = f.alloc(1024 - header());
+        assert_eq!(all as uint, p as uint);
+        assert_eq!(size, 1024 - header());
+    }
+
+    #[test]
+    fn free_list_fragments_until_neighbors_are_freed() {
+        let chunk = 64 + header();
+        let memory = Region::new(4 * chunk, 16);
+        let mut f = unsafe { FreeListAllocator::new(memory.start, 4 * chunk) };
+        let mut ptrs = [0 as *mut u8, ..4];
+        for i in range(0u, 4) {
+            let (ptr, size) = f.alloc(64);
+            assert_eq!(size, 64);
+            ptrs[i] = ptr;
+        }
+        let (_, size) = f.alloc(1);
+        assert_eq!(size, 0);
+
+        // Two free chunks that aren't adjacent can't hold a block of both
+        f.free(ptrs[0]);
+        f.free(ptrs[2]);
+        let (_, size) = f.alloc(128);
+        assert_eq!(size, 0);
+        let (ptr, _) = f.alloc(64);
+        assert_eq!(ptr as uint, ptrs[0] as uint);
+
+        for i in range(0u, 4) {
+            if i != 2 {
+                f.free(ptrs[i]);
+            }
+        }
+        let (_, size) = f.alloc(4 * chunk - header());
+        assert_eq!(size, 4 * chunk - header());
+    }
+
+    #[test]
+    fn free_list_zero_sized_alloc_takes_no_chunk() {
+        let memory = Region::new(1024, 16);
+        let mut f = unsafe { FreeListAllocator::new(memory.start, 1024) };
+        assert_eq!(f.alloc(0), (ZERO_SIZE_PTR, 0));
+        f.free(ZERO_SIZE_PTR);
+        // The whole arena is still one free chunk
+        let (all, size) = f.alloc(1024 - header());
+        assert_eq!(all as uint, memory.start as uint + header());
+        assert_eq!(size, 1024 - header());
+    }
+
+    #[test]
+    fn free_list_rejects_overflowing_sizes() {
+        let memory = Region::new(1024, 16);
+        let mut f = unsafe { FreeListAllocator::new(memory.start, 1024) };
+        for &size in [!0u, !0u - header() + 1, !0u - memory.start as uint].iter() {
+            let (_, got) = f.alloc(size);
+            assert_eq!(got, 0);
+        }
+        // Nothing was split off
+        let (all, size) = f.alloc(1024 - header());
+        assert_eq!(all as uint, memory.start as uint + header());
+        assert_eq!(size, 1024 - header());
+    }
+}
@@ -0,0 +1,89 @@
+use core::option::{Some, None};
+
+use kernel::mm::{Alloc, LockedAllocator};
+
+/// The kernel heap: a locked `Alloc` behind the size-and-alignment
+/// interface the language's allocation hooks expect. Sizes are in bytes,
+/// so the `Alloc` should have `el_size` 0.
+pub struct GlobalBuddy {
+    heap: LockedAllocator<Alloc>
+}
+
+impl GlobalBuddy {
+    /// Run `f` on the underlying `Alloc` with the lock held.
+    pub fn with<R>(&self, f: |&mut Alloc| -> R) -> R {
+        self.heap.with(f)
+    }
+
+    /// `size` bytes aligned to `align`, or null when out of memory.
+    pub unsafe fn allocate(&self, size: uint, align: uint) -> *mut u8 {
+        self.heap.with(|a| a.alloc_layout(size, align))
+    }
+
+    // `reallocate` may leave a block larger than `size` and `align` imply,
+    // so the block is found from the pointer rather than with
+    // `dealloc_layout`.
+    pub unsafe fn deallocate(&self, ptr: *mut u8, _size: uint, _align: uint) {
+        self.heap.free(ptr);
+    }
+
+    /// Resize the block at `ptr`, which holds `old_size` bytes, to `size`
+    /// bytes aligned to `align`. Returns null and leaves `ptr` allocated
+    /// when out of memory.
+    pub unsafe fn reallocate(&self, ptr: *mut u8, _old_size: uint, size: uint,
+                             align: uint) -> *mut u8 {
+        if align <= 1 {
+            return match self.heap.realloc(ptr, size) {
+                (_, 0) if size != 0 => 0 as *mut u8,
+                (ptr, _) => ptr
+            };
+        }
+        // The block's own size bounds the copy, so `old_size` isn't needed
+        self.heap.with(|a| match a.realloc_align(ptr, size, align) {
+            None => 0 as *mut u8,
+            Some((ptr, _)) => ptr
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::intrinsics::move_val_init;
+    use std::mem::{size_of, min_align_of};
+    use std::ptr::read;
+    use kernel::mm::{GlobalBuddy, LockedAllocator};
+    use kernel::mm::testing::Arena;
+
+    struct Counted {
+        value: uint,
+        drops: *mut uint
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            unsafe { *self.drops += 1; }
+        }
+    }
+
+    // The kernel's `exchange_malloc` and `exchange_free` can't replace std's
+    // here, so this does what they do for a box.
+    #[test]
+    fn global_buddy_boxes_and_drops_a_value() {
+        let arena = Arena::bytes(12);
+        let heap = GlobalBuddy { heap: LockedAllocator::new(arena.alloc) };
+        let mut drops = 0u;
+        unsafe {
+            let size = size_of::<Counted>();
+            let align = min_align_of::<Counted>();
+            let ptr = heap.allocate(size, align) as *mut Counted;
+            assert!(ptr as uint != 0);
+            assert_eq!(ptr as uint & (align - 1), 0);
+            move_val_init(&mut *ptr, Counted { value: 42, drops: &mut drops });
+            assert_eq!((*ptr).value, 42);
+            drop(read(ptr as *Counted));
+            heap.deallocate(ptr as *mut u8, size, align);
+        }
+        assert_eq!(drops, 1);
+        assert_eq!(heap.with(|a| a.used_bytes()), 0);
+    }
+}
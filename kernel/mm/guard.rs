@@ -0,0 +1,185 @@
+use core::ptr::{set_memory, copy_memory};
+use core::fail::abort;
+use core::option::{Option, Some, None};
+
+use kernel::mm::Allocator;
+
+/// Bytes of guard on each side of a block, at least
+pub static GUARD_SIZE: uint = 16;
+static GUARD_BYTE: u8 = 0xA5;
+/// Number of allocations a `GuardedAllocator` can track at once
+pub static GUARD_SLOTS: uint = 64;
+
+// Where the inner allocator's block starts and how much of it is in use
+struct Guarded {
+    ptr: *mut u8,
+    front: uint,
+    size: uint,
+    total: uint
+}
+
+/// Surrounds every block from `inner` with guard bytes and aborts on any
+/// `free` that finds them overwritten. Each allocation costs at least
+/// `2 * GUARD_SIZE` extra bytes, or the alignment plus `GUARD_SIZE` for
+/// aligned requests, plus a slot in a table of `GUARD_SLOTS`; requests
+/// fail outright once the table is full. Sizes are taken to be in bytes,
+/// so `inner` should count in bytes too.
+pub struct GuardedAllocator<A> {
+    priv inner: A,
+    priv slots: [Guarded, ..GUARD_SLOTS]
+}
+
+// Whether all `len` bytes from `ptr` still hold the guard pattern
+unsafe fn intact(ptr: *mut u8, len: uint) -> bool {
+    let mut i = 0;
+    while i < len {
+        if *((ptr as uint + i) as *mut u8) != GUARD_BYTE {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+impl<A: Allocator> GuardedAllocator<A> {
+    pub fn new(inner: A) -> GuardedAllocator<A> {
+        GuardedAllocator {
+            inner: inner,
+            slots: [Guarded { ptr: 0 as *mut u8, front: 0, size: 0, total: 0 }, ..GUARD_SLOTS]
+        }
+    }
+
+    pub fn inner<'a>(&'a mut self) -> &'a mut A {
+        &mut self.inner
+    }
+
+    fn slot(&self, ptr: *mut u8) -> Option<uint> {
+        let mut i = 0;
+        while i < GUARD_SLOTS {
+            if self.slots[i].ptr == ptr {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    // Abort if either guard of the block in `slot` is damaged.
+    fn check(&self, slot: uint) {
+        let g = self.slots[slot];
+        let base = g.ptr as uint - g.front;
+        let tail = g.ptr as uint + g.size;
+        unsafe {
+            if !intact(base as *mut u8, g.front) || !intact(tail as *mut u8, base + g.total - tail) {
+                abort();
+            }
+        }
+    }
+}
+
+impl<A: Allocator> Allocator for GuardedAllocator<A> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        // Nothing to guard, and no way to tell success from failure
+        if size == 0 {
+            return (0 as *mut u8, 0);
+        }
+        let slot = match self.slot(0 as *mut u8) {
+            Some(slot) => slot,
+            None => return (0 as *mut u8, 0)
+        };
+        // The front guard is a whole multiple of `align`, so the block
+        // handed out is as aligned as the inner one.
+        let front = if align > GUARD_SIZE { align } else { GUARD_SIZE };
+        match self.inner.alloc_align(size + front + GUARD_SIZE, align) {
+            (ptr, 0) => (ptr, 0),
+            (base, total) => {
+                set_memory(base, GUARD_BYTE, total);
+                let ptr = (base as uint + front) as *mut u8;
+                self.slots[slot] = Guarded { ptr: ptr, front: front, size: size, total: total };
+                (ptr, size)
+            }
+        }
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        let old_size = match self.slot(src) {
+            Some(slot) => {
+                self.check(slot);
+                self.slots[slot].size
+            }
+            None => 0
+        };
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                unsafe {
+                    copy_memory(ptr, src as *u8, if old_size < sz { old_size } else { sz });
+                }
+                self.free(src);
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if ptr as uint == 0 {
+            return;
+        }
+        match self.slot(ptr) {
+            Some(slot) => {
+                self.check(slot);
+                let front = self.slots[slot].front;
+                self.slots[slot].ptr = 0 as *mut u8;
+                self.inner.free((ptr as uint - front) as *mut u8);
+            }
+            // not ours, or already freed
+            None => {}
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.inner.owns(ptr)
+    }
+
+    // The guard doesn't count; only the size asked for is usable.
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        match self.slot(ptr) {
+            Some(slot) => self.slots[slot].size,
+            None => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, GuardedAllocator};
+    use kernel::mm::testing::{Arena, fill};
+
+    #[test]
+    fn guard_allows_writes_within_the_block() {
+        let arena = Arena::bytes(10);
+        let mut g = GuardedAllocator::new(arena.alloc);
+        let (p, size) = g.alloc(32);
+        assert_eq!(size, 32);
+        fill(p, 0, 32);
+        g.free(p);
+        assert_eq!(g.inner().used_bytes(), 0);
+    }
+
+    #[test]
+    #[should_fail]
+    fn guard_detects_an_overrun() {
+        let arena = Arena::bytes(10);
+        let mut g = GuardedAllocator::new(arena.alloc);
+        let (p, _) = g.alloc(32);
+        fill(p, 0, 33);
+        g.free(p);
+    }
+}
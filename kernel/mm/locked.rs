@@ -0,0 +1,99 @@
+use cpu::sync::Spinlock;
+use kernel::mm::Allocator;
+use util::cell::SharedCell;
+
+/// Wraps an allocator in a spinlock taken with IRQs masked, so it can be
+/// shared between CPUs and interrupt handlers through `&self`. This is
+/// what belongs in a static.
+pub struct LockedAllocator<A> {
+    lock: Spinlock,
+    // only reached from `with`, with the lock held
+    inner: SharedCell<A>
+}
+
+impl<A: Allocator> LockedAllocator<A> {
+    pub fn new(inner: A) -> LockedAllocator<A> {
+        LockedAllocator { lock: Spinlock::new(), inner: SharedCell::new(inner) }
+    }
+
+    /// Run `f` on the inner allocator with the lock held. The lock is
+    /// dropped once `f` returns, whatever it returns.
+    pub fn with<R>(&self, f: |&mut A| -> R) -> R {
+        let cpsr = self.lock.lock();
+        let r = f(unsafe { &mut *self.inner.get() });
+        self.lock.unlock(cpsr);
+        r
+    }
+
+    pub fn alloc(&self, size: uint) -> (*mut u8, uint) {
+        self.with(|a| a.alloc(size))
+    }
+
+    pub unsafe fn alloc_align(&self, size: uint, align: uint) -> (*mut u8, uint) {
+        self.with(|a| a.alloc_align(size, align))
+    }
+
+    pub fn zero_alloc(&self, size: uint) -> (*mut u8, uint) {
+        self.with(|a| a.zero_alloc(size))
+    }
+
+    pub fn realloc(&self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        self.with(|a| a.realloc(src, size))
+    }
+
+    pub fn free(&self, ptr: *mut u8) {
+        self.with(|a| a.free(ptr))
+    }
+
+    pub fn owns(&self, ptr: *mut u8) -> bool {
+        self.with(|a| a.owns(ptr))
+    }
+
+    pub fn usable_size(&self, ptr: *mut u8) -> uint {
+        self.with(|a| a.usable_size(ptr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Alloc, LockedAllocator};
+    use kernel::mm::testing::{Arena, fill, peek};
+
+    #[test]
+    fn locked_allocator_shares_between_tasks() {
+        let arena = Arena::bytes(14);
+        let locked = LockedAllocator::new(arena.alloc);
+        // The tasks are all joined before `locked` goes away
+        let addr = &locked as *LockedAllocator<Alloc> as uint;
+        let (tx, rx) = channel();
+        for id in range(0u8, 4) {
+            let tx = tx.clone();
+            spawn(proc() {
+                let locked = unsafe { &*(addr as *LockedAllocator<Alloc>) };
+                for _ in range(0u, 100) {
+                    let mut blocks = [0 as *mut u8, ..16];
+                    for i in range(0u, 16) {
+                        let (p, size) = locked.alloc(32);
+                        assert_eq!(size, 32);
+                        fill(p, id, 32);
+                        blocks[i] = p;
+                    }
+                    // No other task was handed any of these bytes
+                    for &p in blocks.iter() {
+                        for j in range(0u, 32) {
+                            assert_eq!(peek(p, j), id);
+                        }
+                        locked.free(p);
+                    }
+                }
+                tx.send(());
+            });
+        }
+        drop(tx);
+        for _ in range(0u, 4) {
+            rx.recv();
+        }
+        assert_eq!(locked.with(|a| a.used_bytes()), 0);
+        assert_eq!(locked.with(|a| a.parent.check_invariants()), Ok(()));
+    }
+}
@@ -0,0 +1,206 @@
+use kernel::mm::{Allocator, LockedAllocator};
+
+/// Objects a magazine holds
+pub static MAGAZINE_SIZE: uint = 16;
+
+// A stack of cached objects, all of one size
+struct Magazine {
+    rounds: [*mut u8, ..MAGAZINE_SIZE],
+    count: uint
+}
+
+/// Per-CPU front end for objects of one size from a shared
+/// `LockedAllocator`. Frees are pushed on a local magazine and allocations
+/// popped from it without taking the lock; the backing allocator is only
+/// locked to refill an empty magazine or flush a full one, a whole
+/// magazine at a time. A second magazine is kept so that alternating
+/// allocs and frees at a boundary don't go to the backing every time.
+///
+/// Each CPU should have its own cache, run with preemption disabled.
+/// Cached objects stay allocated in the backing until `drain`.
+pub struct MagazineCache<'a, A> {
+    priv backing: &'a LockedAllocator<A>,
+    priv obj_size: uint,
+    priv loaded: Magazine,
+    priv previous: Magazine
+}
+
+impl<'a, A: Allocator> MagazineCache<'a, A> {
+    /// A cache for objects of `obj_size` in `backing`'s units
+    pub fn new(backing: &'a LockedAllocator<A>, obj_size: uint) -> MagazineCache<'a, A> {
+        MagazineCache {
+            backing: backing,
+            obj_size: obj_size,
+            loaded: Magazine { rounds: [0 as *mut u8, ..MAGAZINE_SIZE], count: 0 },
+            previous: Magazine { rounds: [0 as *mut u8, ..MAGAZINE_SIZE], count: 0 }
+        }
+    }
+
+    pub fn object_size(&self) -> uint {
+        self.obj_size
+    }
+
+    /// Take an object, or null when the backing allocator is out of memory.
+    pub fn alloc_object(&mut self) -> *mut u8 {
+        if self.loaded.count == 0 {
+            if self.previous.count != 0 {
+                self.swap();
+            }
+            else {
+                self.refill();
+                if self.loaded.count == 0 {
+                    return 0 as *mut u8;
+                }
+            }
+        }
+        self.loaded.count -= 1;
+        self.loaded.rounds[self.loaded.count]
+    }
+
+    /// Put back an object taken from this cache.
+    pub fn free_object(&mut self, ptr: *mut u8) {
+        if ptr as uint == 0 {
+            return;
+        }
+        if self.loaded.count == MAGAZINE_SIZE {
+            if self.previous.count == MAGAZINE_SIZE {
+                flush(self.backing, &mut self.previous);
+            }
+            self.swap();
+        }
+        self.loaded.rounds[self.loaded.count] = ptr;
+        self.loaded.count += 1;
+    }
+
+    /// Return every cached object to the backing allocator.
+    pub fn drain(&mut self) {
+        flush(self.backing, &mut self.loaded);
+        flush(self.backing, &mut self.previous);
+    }
+
+    fn swap(&mut self) {
+        let loaded = self.loaded;
+        self.loaded = self.previous;
+        self.previous = loaded;
+    }
+
+    // Fill half of the loaded magazine under one lock, leaving room for
+    // frees before it has to be flushed.
+    fn refill(&mut self) {
+        let size = self.obj_size;
+        let mag = &mut self.loaded;
+        self.backing.with(|a| {
+            while mag.count < MAGAZINE_SIZE / 2 {
+                match a.alloc(size) {
+                    (_, 0) => break,
+                    (ptr, _) => {
+                        mag.rounds[mag.count] = ptr;
+                        mag.count += 1;
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Free everything in `mag` under one lock.
+fn flush<A: Allocator>(backing: &LockedAllocator<A>, mag: &mut Magazine) {
+    backing.with(|a| {
+        while mag.count > 0 {
+            mag.count -= 1;
+            a.free(mag.rounds[mag.count]);
+        }
+    });
+}
+
+// Objects are allocated without alignment, so requests for alignment fail
+// along with requests larger than an object.
+impl<'a, A: Allocator> Allocator for MagazineCache<'a, A> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        if size > self.obj_size || align > 1 {
+            return (0 as *mut u8, 0);
+        }
+        let ptr = self.alloc_object();
+        (ptr, if ptr as uint == 0 { 0 } else { self.obj_size })
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free_object(src);
+            return (0 as *mut u8, 0);
+        }
+        if size <= self.obj_size {
+            (src, self.obj_size)
+        }
+        else {
+            (0 as *mut u8, 0)
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        self.free_object(ptr);
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.backing.owns(ptr)
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        self.backing.usable_size(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Alloc, LockedAllocator, MagazineCache, StatsAllocator};
+    use kernel::mm::magazine::MAGAZINE_SIZE;
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn magazine_serves_pairs_without_the_backing() {
+        let arena = Arena::bytes(14);
+        let backing = LockedAllocator::new(StatsAllocator::new(arena.alloc));
+        {
+            let mut cache = MagazineCache::new(&backing, 32);
+            for _ in range(0u, 1000) {
+                let p = cache.alloc_object();
+                assert!(p as uint != 0);
+                cache.free_object(p);
+            }
+            // One refill, and nothing flushed
+            assert_eq!(backing.with(|s| s.stats().allocs), MAGAZINE_SIZE / 2);
+            assert_eq!(backing.with(|s| s.stats().frees), 0);
+            cache.drain();
+        }
+        let stats = backing.with(|s| s.stats());
+        assert_eq!(stats.frees, stats.allocs);
+        assert_eq!(stats.outstanding, 0);
+    }
+
+    #[test]
+    fn magazine_batches_bursts() {
+        let arena = Arena::bytes(14);
+        let backing: LockedAllocator<StatsAllocator<Alloc>> =
+            LockedAllocator::new(StatsAllocator::new(arena.alloc));
+        let mut cache = MagazineCache::new(&backing, 32);
+        // Bursts that fit in the two magazines
+        let mut blocks = [0 as *mut u8, ..24];
+        for _ in range(0u, 25) {
+            for i in range(0u, 24) {
+                blocks[i] = cache.alloc_object();
+            }
+            for &p in blocks.iter() {
+                cache.free_object(p);
+            }
+        }
+        // 600 pairs, but the backing only saw the first burst's refills
+        let stats = backing.with(|s| s.stats());
+        assert_eq!(stats.allocs, 24);
+        assert_eq!(stats.frees, 0);
+        cache.drain();
+        assert_eq!(backing.with(|s| s.stats().outstanding), 0);
+    }
+}
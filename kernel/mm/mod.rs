@@ -1,9 +1,83 @@
 pub use self::allocator::{
 	Allocator,
+	AllocResult,
+	bitv_bytes_for_order,
+	heap_init,
+	snapshot_diff,
+	MAX_RESERVED,
+	MAX_PINNED,
+	MAX_PADDED,
+	ZERO_SIZE_PTR,
+	zero_size_ptr,
+	is_zero_size,
 	BuddyAlloc,
 	Alloc,
+	Node,
+	Extents,
+	MemoryMap,
+	LiveAllocations,
+	MemoryRegion,
+	SplitPolicy,
+	LeftFirst,
+	RightFirst,
+	AllocEvent,
+	EventOp,
+	Allocated,
+	Freed,
+	Events,
+	AllocError,
+	OutOfMemory,
+	SizeTooLarge,
+	TooManyPadded,
+	FreeError,
+	NotOwned,
+	DoubleFree,
+	InteriorPointer,
+	Reserved,
 };
+pub use self::bitmap::BitmapAlloc;
+pub use self::boxed::AllocBox;
+pub use self::bump::BumpAllocator;
+pub use self::fallback::FallbackAllocator;
+pub use self::frame::FrameAllocator;
+pub use self::freelist::FreeListAllocator;
+pub use self::global::GlobalBuddy;
+pub use self::guard::GuardedAllocator;
+pub use self::slab::SlabAllocator;
+pub use self::locked::LockedAllocator;
+pub use self::magazine::MagazineCache;
+pub use self::null::NullAllocator;
+pub use self::pool::FixedPoolAllocator;
+pub use self::segregated::SegregatedAllocator;
+pub use self::stack::{StackAllocator, StackMark};
+pub use self::stats::{StatsAllocator, AllocStats};
+pub use self::tlsf::TlsfAllocator;
+pub use self::tracing::TracingAllocator;
+pub use self::zoned::{ZonedAllocator, MAX_ZONES};
 
 pub mod allocator;
+pub mod bitmap;
+pub mod boxed;
+pub mod bump;
+pub mod fallback;
+pub mod frame;
+pub mod freelist;
+pub mod global;
+pub mod guard;
+pub mod locked;
+pub mod magazine;
+pub mod null;
+#[cfg(not(test))]
 pub mod physical;
+pub mod pool;
+pub mod segregated;
+pub mod slab;
+pub mod stack;
+pub mod stats;
+#[cfg(test)]
+pub mod testing;
+pub mod tlsf;
+pub mod tracing;
+#[cfg(not(test))]
 pub mod virtual;
+pub mod zoned;
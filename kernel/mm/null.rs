@@ -0,0 +1,48 @@
+use kernel::mm::Allocator;
+
+/// Fails every request, for exercising out-of-memory paths. It owns no
+/// memory, so `free` does nothing.
+pub struct NullAllocator;
+
+impl Allocator for NullAllocator {
+    unsafe fn alloc_align(&mut self, _: uint, _: uint) -> (*mut u8, uint) {
+        (0 as *mut u8, 0)
+    }
+
+    fn zero_alloc(&mut self, _: uint) -> (*mut u8, uint) {
+        (0 as *mut u8, 0)
+    }
+
+    fn realloc(&mut self, _: *mut u8, _: uint) -> (*mut u8, uint) {
+        (0 as *mut u8, 0)
+    }
+
+    fn free(&mut self, _: *mut u8) {
+    }
+
+    fn owns(&self, _: *mut u8) -> bool {
+        false
+    }
+
+    fn usable_size(&self, _: *mut u8) -> uint {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, NullAllocator};
+
+    #[test]
+    fn null_fails_everything() {
+        let mut n = NullAllocator;
+        let (p, size) = n.alloc(16);
+        assert_eq!((p as uint, size), (0, 0));
+        let (p, size) = n.zero_alloc(16);
+        assert_eq!((p as uint, size), (0, 0));
+        let (p, size) = n.realloc(0x1000 as *mut u8, 16);
+        assert_eq!((p as uint, size), (0, 0));
+        n.free(0x1000 as *mut u8);
+        assert!(!n.owns(0x1000 as *mut u8));
+    }
+}
@@ -1,18 +1,22 @@
 use core::fail::abort;
 use core::mem::transmute;
+use core::option::{Some, None};
 
 use kernel::heap;
 use kernel::mm;
-use kernel::mm::Allocator;
 use cpu::mmu::Frame;
 use util::bitv;
 
-pub static mut frames: mm::Alloc = mm::Alloc {
-    base: 0x200_000 as *mut u8,
-    el_size: 12,
-    parent: mm::BuddyAlloc {
-        order: 13,
-        tree: bitv::Bitv { storage: 0 as *mut u32 }
+pub static mut frames: mm::FrameAllocator = mm::FrameAllocator {
+    alloc: mm::Alloc {
+        base: 0x200_000 as *mut u8,
+        el_size: 12,
+        canaries: 0 as *mut uint,
+        dirty: 0 as *mut u32,
+        oom_handler: None,
+        padded: [(0, 0), ..mm::MAX_PADDED],
+        padded_count: 0,
+        parent: buddy_alloc_static!(13, 0)
     }
 };
 
@@ -40,25 +44,27 @@ impl<T> Phys<T> {
 
 pub fn init() {
     unsafe {
-        frames.parent.tree.storage = heap::zero_alloc::<u32>(1024);
+        let tree = &mut frames.inner().parent;
+        let storage = heap::alloc::<u32>(bitv::Bitv::bytes_for_order(tree.order) / 4);
+        tree.tree = bitv::Bitv::from_storage(storage, tree.order);
     }
 }
 
 pub unsafe fn alloc_frames<T = Frame>(count: uint) -> Phys<T> {
-    match frames.alloc(count) {
-        (_, 0) => abort(),
-        (ptr, _) => Phys { ptr: ptr as *mut T }
+    match frames.alloc_frames(count) {
+        None => abort(),
+        Some((ptr, _)) => Phys { ptr: ptr as *mut T }
     }
 }
 
 pub unsafe fn zero_alloc_frames<T = Frame>(count: uint) -> Phys<T> {
-    match frames.zero_alloc(count) {
-        (_, 0) => abort(),
-        (ptr, _) => Phys { ptr: ptr as *mut T }
+    match frames.zero_alloc_frames(count) {
+        None => abort(),
+        Some((ptr, _)) => Phys { ptr: ptr as *mut T }
     }
 }
 
 #[inline]
 pub unsafe fn free_frames<T>(ptr: Phys<T>) {
-    frames.free(ptr.offset() as *mut u8);
+    frames.free_frames(ptr.offset() as *mut u8);
 }
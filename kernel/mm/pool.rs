@@ -0,0 +1,243 @@
+use core::mem::size_of;
+use core::ptr::set_memory;
+
+use kernel::mm::{Allocator, zero_size_ptr, is_zero_size};
+use util::ptr::mut_offset;
+
+// A free slot links to the next free slot
+struct Slot {
+    next: *mut Slot
+}
+
+/// Splits a region into equal slots kept on a stack threaded through the
+/// free slots, so `alloc` and `free` are O(1) and never fragment. The most
+/// recently freed slot is reused first. A bit per slot records which are
+/// allocated, so that freeing a slot twice can't put it on the stack twice.
+pub struct FixedPoolAllocator {
+    priv base: *mut u8,
+    priv end: *mut u8,
+    priv obj_size: uint,
+    priv align: uint,
+    priv free: *mut Slot,
+    // one bit per slot, set while it is allocated
+    priv used: *mut u32
+}
+
+impl FixedPoolAllocator {
+    /// Bytes of occupancy bitmap a pool of `size` bytes split into
+    /// `obj_size` slots needs, at most
+    pub fn bytes_for(size: uint, obj_size: uint) -> uint {
+        let slots = if obj_size == 0 { size } else { size / obj_size };
+        (slots + 31) / 32 * 4
+    }
+
+    /// Split the `size` bytes at `base` into slots of at least `obj_size`
+    /// bytes, tracked in `used`, which must hold `bytes_for(size, obj_size)`
+    /// bytes.
+    pub unsafe fn new(base: *mut u8, size: uint, obj_size: uint,
+                      used: *mut u32) -> FixedPoolAllocator {
+        // Free slots hold a link, so they are at least a word.
+        let word = size_of::<*mut Slot>();
+        let obj_size = (obj_size + word - 1) & !(word - 1);
+        let obj_size = if obj_size < word { word } else { obj_size };
+        let start = (base as uint + word - 1) & !(word - 1);
+        let count = if base as uint + size > start {
+            (base as uint + size - start) / obj_size
+        } else {
+            0
+        };
+
+        let mut pool = FixedPoolAllocator {
+            base: start as *mut u8,
+            end: (start + count * obj_size) as *mut u8,
+            obj_size: obj_size,
+            // every slot is aligned to the lowest bit set in start or size
+            align: (start | obj_size) & !((start | obj_size) - 1),
+            free: 0 as *mut Slot,
+            used: used
+        };
+        set_memory(used as *mut u8, 0, (count + 31) / 32 * 4);
+
+        // Push from the top so the lowest slot is handed out first
+        let mut slot = pool.end as uint;
+        while slot > start {
+            slot -= obj_size;
+            pool.push(slot as *mut u8);
+        }
+        pool
+    }
+
+    pub fn object_size(&self) -> uint {
+        self.obj_size
+    }
+
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let slot = ptr as *mut Slot;
+        (*slot).next = self.free;
+        self.free = slot;
+    }
+
+    // The word of the bitmap holding the bit of the slot at `ptr`, and that
+    // bit, or `None` if `ptr` isn't the start of a slot
+    fn bit_of(&self, ptr: *mut u8) -> Option<(*mut u32, u32)> {
+        if ptr < self.base || ptr >= self.end {
+            return None;
+        }
+        let offset = ptr as uint - self.base as uint;
+        if offset % self.obj_size != 0 {
+            return None;
+        }
+        let i = offset / self.obj_size;
+        Some((unsafe { mut_offset(self.used, (i / 32) as int) }, 1 << (i % 32)))
+    }
+
+    // Whether `ptr` is the start of an allocated slot
+    fn is_allocated(&self, ptr: *mut u8) -> bool {
+        match self.bit_of(ptr) {
+            Some((word, bit)) => unsafe { *word & bit != 0 },
+            None => false
+        }
+    }
+}
+
+// Requests larger or more aligned than a slot fail, returning null.
+// Zero-sized requests take no slot.
+impl Allocator for FixedPoolAllocator {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        if size == 0 {
+            return (zero_size_ptr(align), 0);
+        }
+        if size > self.obj_size || align > self.align || self.free as uint == 0 {
+            return (0 as *mut u8, 0);
+        }
+        let slot = self.free;
+        self.free = (*slot).next;
+        match self.bit_of(slot as *mut u8) {
+            Some((word, bit)) => *word |= bit,
+            None => {}
+        }
+        (slot as *mut u8, self.obj_size)
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 || is_zero_size(src) {
+            return self.alloc(size);
+        }
+        if !self.is_allocated(src) {
+            return (0 as *mut u8, 0);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        if size <= self.obj_size {
+            (src, self.obj_size)
+        }
+        else {
+            (0 as *mut u8, 0)
+        }
+    }
+
+    // Ignores pointers that aren't the start of an allocated slot, so a
+    // second free of the same slot does nothing.
+    fn free(&mut self, ptr: *mut u8) {
+        match self.bit_of(ptr) {
+            Some((word, bit)) => unsafe {
+                if *word & bit == 0 {
+                    return;
+                }
+                *word &= !bit;
+                self.push(ptr);
+            },
+            None => {}
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.base && ptr < self.end
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.owns(ptr) { self.obj_size } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, FixedPoolAllocator, ZERO_SIZE_PTR};
+    use kernel::mm::testing::Region;
+
+    // A pool of `size` bytes at `memory` with its bitmap in `used`
+    fn new_pool(memory: &Region, size: uint, obj_size: uint,
+                used: &mut Vec<u32>) -> FixedPoolAllocator {
+        *used = Vec::from_elem(FixedPoolAllocator::bytes_for(size, obj_size) / 4, 0u32);
+        unsafe { FixedPoolAllocator::new(memory.start, size, obj_size, used.as_mut_ptr()) }
+    }
+
+    #[test]
+    fn pool_is_exhausted_after_every_slot() {
+        let memory = Region::new(256, 64);
+        let mut used = Vec::new();
+        let mut pool = new_pool(&memory, 256, 32, &mut used);
+        for i in range(0u, 8) {
+            let (ptr, size) = pool.alloc(32);
+            assert_eq!(ptr as uint, memory.start as uint + i * 32);
+            assert_eq!(size, 32);
+        }
+        let (_, size) = pool.alloc(1);
+        assert_eq!(size, 0);
+        // Too large never fits, even once slots are free
+        pool.free(memory.start);
+        let (_, size) = pool.alloc(33);
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn pool_reuses_the_last_slot_freed() {
+        let memory = Region::new(256, 64);
+        let mut used = Vec::new();
+        let mut pool = new_pool(&memory, 256, 32, &mut used);
+        let (a, _) = pool.alloc(32);
+        let (b, _) = pool.alloc(32);
+        let (c, _) = pool.alloc(32);
+        pool.free(a);
+        pool.free(c);
+        pool.free(b);
+        assert_eq!(pool.alloc(32), (b, 32));
+        assert_eq!(pool.alloc(32), (c, 32));
+        assert_eq!(pool.alloc(32), (a, 32));
+    }
+
+    #[test]
+    fn pool_ignores_a_second_free() {
+        let memory = Region::new(256, 64);
+        let mut used = Vec::new();
+        let mut pool = new_pool(&memory, 256, 32, &mut used);
+        let (a, _) = pool.alloc(32);
+        let (b, _) = pool.alloc(32);
+        pool.free(a);
+        pool.free(a);
+        // `a` is handed out once, then the untouched slots
+        assert_eq!(pool.alloc(32), (a, 32));
+        let (c, _) = pool.alloc(32);
+        assert!(c != a && c != b);
+        // Nor can a freed slot be resized back to life
+        pool.free(c);
+        assert_eq!(pool.realloc(c, 16), (0 as *mut u8, 0));
+    }
+
+    #[test]
+    fn pool_zero_sized_and_failed_requests_take_no_slot() {
+        let memory = Region::new(64, 64);
+        let mut used = Vec::new();
+        let mut pool = new_pool(&memory, 64, 32, &mut used);
+        assert_eq!(pool.alloc(0), (ZERO_SIZE_PTR, 0));
+        assert_eq!(pool.alloc(33), (0 as *mut u8, 0));
+        pool.free(ZERO_SIZE_PTR);
+        let (a, _) = pool.alloc(32);
+        let (b, _) = pool.alloc(32);
+        assert_eq!(a, memory.start);
+        assert_eq!(b as uint, memory.start as uint + 32);
+        assert_eq!(pool.alloc(1), (0 as *mut u8, 0));
+    }
+}
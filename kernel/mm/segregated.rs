@@ -0,0 +1,121 @@
+use core::ptr::copy_memory;
+
+use kernel::mm::Allocator;
+
+/// Sends requests of at most `threshold` to `small` and larger ones to
+/// `large`. Frees go to whichever of the two owns the pointer.
+pub struct SegregatedAllocator<S, L> {
+    priv small: S,
+    priv large: L,
+    priv threshold: uint
+}
+
+impl<S: Allocator, L: Allocator> SegregatedAllocator<S, L> {
+    pub fn new(small: S, large: L, threshold: uint) -> SegregatedAllocator<S, L> {
+        SegregatedAllocator { small: small, large: large, threshold: threshold }
+    }
+
+    pub fn threshold(&self) -> uint {
+        self.threshold
+    }
+
+    pub fn small<'a>(&'a mut self) -> &'a mut S {
+        &mut self.small
+    }
+
+    pub fn large<'a>(&'a mut self) -> &'a mut L {
+        &mut self.large
+    }
+}
+
+impl<S: Allocator, L: Allocator> Allocator for SegregatedAllocator<S, L> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        if size <= self.threshold {
+            self.small.alloc_align(size, align)
+        }
+        else {
+            self.large.alloc_align(size, align)
+        }
+    }
+
+    // A block that crosses the threshold moves to the other allocator.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        let small = self.small.owns(src);
+        if small == (size <= self.threshold) {
+            return if small {
+                self.small.realloc(src, size)
+            }
+            else {
+                self.large.realloc(src, size)
+            };
+        }
+
+        // Moving down, only `size` bytes are kept; moving up, all of the
+        // old block is.
+        let count = if small { self.small.usable_size(src) } else { size };
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                unsafe {
+                    copy_memory(ptr, src as *u8, if count < sz { count } else { sz });
+                }
+                self.free(src);
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if self.small.owns(ptr) {
+            self.small.free(ptr);
+        }
+        else if self.large.owns(ptr) {
+            self.large.free(ptr);
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.small.owns(ptr) || self.large.owns(ptr)
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.small.owns(ptr) {
+            self.small.usable_size(ptr)
+        }
+        else {
+            self.large.usable_size(ptr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, SegregatedAllocator};
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn segregated_routes_by_size() {
+        let small = Arena::bytes(10);
+        let large = Arena::new(6, 6);
+        let mut s = SegregatedAllocator::new(small.alloc, large.alloc, 64);
+        let (p, _) = s.alloc(64);
+        let (q, _) = s.alloc(65);
+        assert!(s.small().owns(p));
+        assert!(s.large().owns(q));
+        assert_eq!(s.small().used_bytes(), 64);
+        assert_eq!(s.large().used_bytes(), 128);
+
+        s.free(p);
+        assert_eq!(s.small().used_bytes(), 0);
+        assert_eq!(s.large().used_bytes(), 128);
+        s.free(q);
+        assert_eq!(s.large().used_bytes(), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use core::mem::size_of;
+use core::fail::assert;
+
+use cpu::mmu::PAGE_SIZE;
+use kernel::mm::{Allocator, Alloc};
+
+// Header at the start of every slab page
+struct Slab {
+    next: *mut Slab,
+    free: *mut Object,
+    live: uint
+}
+
+// A free slot links to the next free slot of its slab
+struct Object {
+    next: *mut Object
+}
+
+/// Carves pages taken from an `Alloc` into objects of a single size. Each
+/// slab keeps its free slots in a list threaded through the slots, and its
+/// page goes back to the `Alloc` as soon as the last object is freed.
+pub struct SlabAllocator<'a> {
+    priv backing: &'a mut Alloc,
+    priv slabs: *mut Slab,
+    priv obj_size: uint,
+    priv first: uint
+}
+
+impl<'a> SlabAllocator<'a> {
+    pub fn new(backing: &'a mut Alloc, obj_size: uint) -> SlabAllocator<'a> {
+        // Slots hold a link while free, so they are at least a word.
+        let word = size_of::<*mut Object>();
+        let size = (obj_size + word - 1) & !(word - 1);
+        let size = if size < word { word } else { size };
+        let align = size & !(size - 1);
+        let first = (size_of::<Slab>() + align - 1) & !(align - 1);
+        assert(first + size <= PAGE_SIZE);
+
+        SlabAllocator {
+            backing: backing,
+            slabs: 0 as *mut Slab,
+            obj_size: size,
+            first: first
+        }
+    }
+
+    pub fn object_size(&self) -> uint {
+        self.obj_size
+    }
+
+    /// Take an object, or null when no page can be had for a new slab.
+    pub fn alloc_object(&mut self) -> *mut u8 {
+        unsafe {
+            let mut slab = self.slabs;
+            while slab as uint != 0 && (*slab).free as uint == 0 {
+                slab = (*slab).next;
+            }
+            if slab as uint == 0 {
+                slab = self.grow();
+                if slab as uint == 0 {
+                    return 0 as *mut u8;
+                }
+            }
+
+            let obj = (*slab).free;
+            (*slab).free = (*obj).next;
+            (*slab).live += 1;
+            obj as *mut u8
+        }
+    }
+
+    /// Put back an object. Pointers outside this allocator's slabs are
+    /// ignored.
+    pub fn free_object(&mut self, ptr: *mut u8) {
+        unsafe {
+            let page = (ptr as uint & !(PAGE_SIZE - 1)) as *mut Slab;
+            let mut prev = 0 as *mut Slab;
+            let mut slab = self.slabs;
+            while slab as uint != 0 && slab != page {
+                prev = slab;
+                slab = (*slab).next;
+            }
+            if slab as uint == 0 {
+                return;
+            }
+
+            let obj = ptr as *mut Object;
+            (*obj).next = (*slab).free;
+            (*slab).free = obj;
+            (*slab).live -= 1;
+
+            if (*slab).live == 0 {
+                if prev as uint == 0 {
+                    self.slabs = (*slab).next;
+                }
+                else {
+                    (*prev).next = (*slab).next;
+                }
+                self.backing.free(slab as *mut u8);
+            }
+        }
+    }
+
+    // Start a slab on a new page with all of its slots free.
+    unsafe fn grow(&mut self) -> *mut Slab {
+        let page = self.backing.alloc_page();
+        if page as uint == 0 {
+            return 0 as *mut Slab;
+        }
+
+        let slab = page as *mut Slab;
+        (*slab).next = self.slabs;
+        (*slab).free = 0 as *mut Object;
+        (*slab).live = 0;
+
+        // Link the slots from the top so the lowest one is handed out first
+        let count = (PAGE_SIZE - self.first) / self.obj_size;
+        let mut offset = self.first + count * self.obj_size;
+        while offset > self.first {
+            offset -= self.obj_size;
+            let obj = (page as uint + offset) as *mut Object;
+            (*obj).next = (*slab).free;
+            (*slab).free = obj;
+        }
+
+        self.slabs = slab;
+        slab
+    }
+}
+
+// Requests larger or more aligned than an object fail.
+impl<'a> Allocator for SlabAllocator<'a> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        let obj_align = self.obj_size & !(self.obj_size - 1);
+        if size > self.obj_size || align > obj_align {
+            return (0 as *mut u8, 0);
+        }
+        let ptr = self.alloc_object();
+        (ptr, if ptr as uint == 0 { 0 } else { self.obj_size })
+    }
+
+    // Fails for a `src` outside this allocator's slabs, which `free_object`
+    // would ignore.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if !self.owns(src) {
+            return (0 as *mut u8, 0);
+        }
+        if size == 0 {
+            self.free_object(src);
+            return (0 as *mut u8, 0);
+        }
+        if size <= self.obj_size {
+            (src, self.obj_size)
+        }
+        else {
+            (0 as *mut u8, 0)
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        self.free_object(ptr);
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        let page = (ptr as uint & !(PAGE_SIZE - 1)) as *mut Slab;
+        let mut slab = self.slabs;
+        while slab as uint != 0 {
+            if slab == page {
+                return true;
+            }
+            slab = unsafe { (*slab).next };
+        }
+        false
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.owns(ptr) { self.obj_size } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::mmu::PAGE_SIZE;
+    use kernel::mm::{Allocator, SlabAllocator};
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn slab_reuses_freed_objects() {
+        let mut pages = Arena::new(4, 12);
+        let mut s = SlabAllocator::new(&mut pages.alloc, 48);
+        assert_eq!(s.object_size(), 48);
+        let p = s.alloc_object();
+        let q = s.alloc_object();
+        assert_eq!((p as uint) & !(PAGE_SIZE - 1), (q as uint) & !(PAGE_SIZE - 1));
+        assert_eq!(q as uint, p as uint + 48);
+        s.free_object(q);
+        assert_eq!(s.alloc_object() as uint, q as uint);
+        s.free_object(p);
+        assert_eq!(s.alloc_object() as uint, p as uint);
+    }
+
+    #[test]
+    fn slab_pages_go_back_once_empty() {
+        let mut pages = Arena::new(4, 12);
+        {
+            let mut s = SlabAllocator::new(&mut pages.alloc, 64);
+            // Enough objects to need a second slab
+            let mut ptrs = [0 as *mut u8, ..80];
+            for i in range(0u, 80) {
+                ptrs[i] = s.alloc_object();
+                assert!(ptrs[i] as uint != 0);
+            }
+            for &ptr in ptrs.iter() {
+                s.free_object(ptr);
+            }
+            assert!(!s.owns(ptrs[0]));
+            assert!(!s.owns(ptrs[79]));
+        }
+        assert_eq!(pages.alloc.used_bytes(), 0);
+    }
+
+    #[test]
+    fn slab_realloc_fails_for_a_pointer_it_does_not_own() {
+        let mut pages = Arena::new(4, 12);
+        let (other, _) = pages.alloc.alloc(1);
+        {
+            let mut s = SlabAllocator::new(&mut pages.alloc, 32);
+            let p = s.alloc_object();
+            assert_eq!(s.realloc(other, 16), (0 as *mut u8, 0));
+            assert_eq!(s.realloc(p, 16), (p, 32));
+        }
+        assert_eq!(pages.alloc.usable_size(other), PAGE_SIZE);
+    }
+}
@@ -0,0 +1,175 @@
+use core::mem::size_of;
+use core::ptr::copy_memory;
+use core::fail::assert;
+
+use kernel::mm::Allocator;
+
+// Stored just below each allocation so the top one can be popped
+struct Frame {
+    top: *mut u8,
+    last: *mut u8
+}
+
+/// Position in a `StackAllocator`, returned by `mark` and later passed to
+/// `release`.
+pub struct StackMark {
+    priv top: *mut u8,
+    priv last: *mut u8
+}
+
+/// Bump allocator over `[base, end)` that can rewind. `release` frees
+/// everything allocated since a `mark`, and `free` pops the most recent
+/// allocation; freeing anything else is ignored.
+pub struct StackAllocator {
+    priv base: *mut u8,
+    priv top: *mut u8,
+    priv end: *mut u8,
+    // most recent live allocation, or null
+    priv last: *mut u8
+}
+
+impl StackAllocator {
+    pub fn new(base: *mut u8, end: *mut u8) -> StackAllocator {
+        StackAllocator { base: base, top: base, end: end, last: 0 as *mut u8 }
+    }
+
+    pub fn mark(&self) -> StackMark {
+        StackMark { top: self.top, last: self.last }
+    }
+
+    /// Rewind to `mark`, freeing every allocation made after it. Marks
+    /// taken after `mark` are invalidated.
+    pub fn release(&mut self, mark: StackMark) {
+        assert(mark.top >= self.base && mark.top <= self.top);
+        self.top = mark.top;
+        self.last = mark.last;
+    }
+}
+
+impl Allocator for StackAllocator {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        assert(align != 0 && align & (align - 1) == 0);
+        let word = size_of::<uint>();
+        let align = if align < word { word } else { align };
+        let top = self.top as uint;
+        let hdr = size_of::<Frame>();
+        let start = (top + hdr + align - 1) & !(align - 1);
+        if start < top || start > self.end as uint || size > self.end as uint - start {
+            return (self.base, 0);
+        }
+
+        let frame = (start - hdr) as *mut Frame;
+        (*frame).top = self.top;
+        (*frame).last = self.last;
+        // keep the next frame word-aligned
+        let size = (size + word - 1) & !(word - 1);
+        self.top = (start + size) as *mut u8;
+        self.last = start as *mut u8;
+        (self.last, size)
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        // The top allocation can grow or shrink in place
+        if src == self.last {
+            let word = size_of::<uint>();
+            let size = (size + word - 1) & !(word - 1);
+            if size <= self.end as uint - src as uint {
+                self.top = (src as uint + size) as *mut u8;
+                return (src, size);
+            }
+            return (self.base, 0);
+        }
+        // Sizes of older allocations aren't recorded; copy up to the top
+        let avail = if src >= self.base && src < self.top {
+            self.top as uint - src as uint
+        }
+        else {
+            0
+        };
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                unsafe {
+                    copy_memory(ptr, src as *u8, if avail < sz { avail } else { sz });
+                }
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if ptr as uint == 0 || ptr != self.last {
+            return;
+        }
+        unsafe {
+            let frame = (ptr as uint - size_of::<Frame>()) as *mut Frame;
+            self.top = (*frame).top;
+            self.last = (*frame).last;
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.base && ptr < self.end
+    }
+
+    // Sizes aren't recorded; everything below `top` is in use.
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if ptr >= self.base && ptr < self.top {
+            self.top as uint - ptr as uint
+        }
+        else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, StackAllocator};
+    use kernel::mm::testing::Region;
+
+    #[test]
+    fn stack_rewinds_to_nested_marks() {
+        let memory = Region::new(1024, 16);
+        let mut s = StackAllocator::new(memory.start, memory.end());
+        let outer = s.mark();
+        let (a, _) = s.alloc(32);
+        let inner = s.mark();
+        let (b, _) = s.alloc(32);
+        s.alloc(32);
+
+        // Only what came after the inner mark is given back
+        s.release(inner);
+        let (c, _) = s.alloc(32);
+        assert_eq!(c as uint, b as uint);
+        assert!(s.usable_size(a) != 0);
+
+        s.release(outer);
+        assert_eq!(s.usable_size(a), 0);
+        let (d, _) = s.alloc(32);
+        assert_eq!(d as uint, a as uint);
+    }
+
+    #[test]
+    fn stack_free_pops_only_the_top() {
+        let memory = Region::new(1024, 16);
+        let mut s = StackAllocator::new(memory.start, memory.end());
+        let (a, _) = s.alloc(16);
+        let (b, _) = s.alloc(16);
+        // `a` isn't on top, so this is ignored
+        s.free(a);
+        let (c, _) = s.alloc(16);
+        assert!(c > b);
+        s.free(c);
+        s.free(b);
+        let (d, _) = s.alloc(16);
+        assert_eq!(d as uint, b as uint);
+    }
+}
@@ -0,0 +1,214 @@
+use kernel::mm::{Allocator, is_zero_size};
+
+/// Counters kept by a `StatsAllocator`. Failed requests are not counted,
+/// nor are zero-sized ones, which take no block.
+pub struct AllocStats {
+    allocs: uint,
+    frees: uint,
+    // sizes passed in, in the inner allocator's units
+    bytes_requested: uint,
+    // sizes handed back after rounding
+    bytes_returned: uint,
+    // allocations not yet freed
+    outstanding: uint,
+    peak_outstanding: uint
+}
+
+/// Forwards every call to `inner`, counting allocations and frees. Block
+/// sizes aren't known on `free`, so outstanding use is counted in
+/// allocations rather than bytes.
+pub struct StatsAllocator<A> {
+    priv inner: A,
+    priv stats: AllocStats
+}
+
+impl<A: Allocator> StatsAllocator<A> {
+    pub fn new(inner: A) -> StatsAllocator<A> {
+        StatsAllocator {
+            inner: inner,
+            stats: AllocStats {
+                allocs: 0,
+                frees: 0,
+                bytes_requested: 0,
+                bytes_returned: 0,
+                outstanding: 0,
+                peak_outstanding: 0
+            }
+        }
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    /// Start a new measurement window: the totals go back to zero and the
+    /// peak to the allocations outstanding now, which are still tracked so
+    /// that freeing them later is counted. Allocations are untouched; use
+    /// `BuddyAlloc::reset_peak` for the tree's own peak.
+    pub fn reset_stats(&mut self) {
+        self.stats = AllocStats {
+            allocs: 0,
+            frees: 0,
+            bytes_requested: 0,
+            bytes_returned: 0,
+            outstanding: self.stats.outstanding,
+            peak_outstanding: self.stats.outstanding
+        };
+    }
+
+    pub fn inner<'a>(&'a mut self) -> &'a mut A {
+        &mut self.inner
+    }
+
+    fn count_alloc(&mut self, size: uint, r: (*mut u8, uint)) {
+        match r {
+            (_, 0) => {}
+            (ptr, _) if is_zero_size(ptr) => {}
+            (_, sz) => {
+                self.stats.allocs += 1;
+                self.stats.bytes_requested += size;
+                self.stats.bytes_returned += sz;
+                self.stats.outstanding += 1;
+                if self.stats.outstanding > self.stats.peak_outstanding {
+                    self.stats.peak_outstanding = self.stats.outstanding;
+                }
+            }
+        }
+    }
+
+    fn count_free(&mut self, ptr: *mut u8) {
+        if ptr as uint != 0 && !is_zero_size(ptr) && self.stats.outstanding > 0 {
+            self.stats.frees += 1;
+            self.stats.outstanding -= 1;
+        }
+    }
+}
+
+impl<A: Allocator> Allocator for StatsAllocator<A> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        let r = self.inner.alloc_align(size, align);
+        self.count_alloc(size, r);
+        r
+    }
+
+    fn zero_alloc(&mut self, size: uint) -> (*mut u8, uint) {
+        let r = self.inner.zero_alloc(size);
+        self.count_alloc(size, r);
+        r
+    }
+
+    // A null or zero-sized `src` counts as an allocation and a zero `size`
+    // as a free. Resizing a live block only adds to the byte totals.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        let r = self.inner.realloc(src, size);
+        if src as uint == 0 || is_zero_size(src) {
+            self.count_alloc(size, r);
+        }
+        else if size == 0 {
+            self.count_free(src);
+        }
+        else {
+            match r {
+                (_, 0) => {}
+                (_, sz) => {
+                    self.stats.bytes_requested += size;
+                    self.stats.bytes_returned += sz;
+                }
+            }
+        }
+        r
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        self.inner.free(ptr);
+        self.count_free(ptr);
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.inner.owns(ptr)
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        self.inner.usable_size(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, StatsAllocator, ZERO_SIZE_PTR};
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn stats_count_a_known_sequence() {
+        let arena = Arena::bytes(10);
+        let mut s = StatsAllocator::new(arena.alloc);
+        let (a, _) = s.alloc(20);
+        let (b, _) = s.alloc(100);
+        s.free(a);
+        s.realloc(b, 200);
+        let (d, _) = s.realloc(0 as *mut u8, 10);
+        s.realloc(d, 0);
+        // Failures and null frees aren't counted
+        s.alloc(4096);
+        s.free(0 as *mut u8);
+
+        let stats = s.stats();
+        assert_eq!(stats.allocs, 3);
+        assert_eq!(stats.frees, 2);
+        assert_eq!(stats.bytes_requested, 20 + 100 + 200 + 10);
+        assert_eq!(stats.bytes_returned, 32 + 128 + 256 + 16);
+        assert_eq!(stats.outstanding, 1);
+        assert_eq!(stats.peak_outstanding, 2);
+    }
+
+    #[test]
+    fn reset_stats_starts_a_fresh_window() {
+        let arena = Arena::bytes(10);
+        let mut s = StatsAllocator::new(arena.alloc);
+        let (a, _) = s.alloc(20);
+        let (b, _) = s.alloc(100);
+        s.alloc(8);
+        s.free(a);
+        s.reset_stats();
+        let stats = s.stats();
+        assert_eq!((stats.allocs, stats.frees), (0, 0));
+        assert_eq!(stats.outstanding, 2);
+        assert_eq!(stats.peak_outstanding, 2);
+
+        // Only what happens from here on is counted
+        s.free(b);
+        let (c, _) = s.alloc(50);
+        let stats = s.stats();
+        assert_eq!((stats.allocs, stats.frees), (1, 1));
+        assert_eq!(stats.bytes_requested, 50);
+        assert_eq!(stats.bytes_returned, 64);
+        assert_eq!(stats.outstanding, 2);
+        assert_eq!(stats.peak_outstanding, 2);
+        // The allocations made before the reset are still live
+        assert_eq!(s.inner().used_bytes(), 8 + 64);
+        s.free(c);
+    }
+
+    #[test]
+    fn zero_sized_allocations_are_not_counted() {
+        let arena = Arena::bytes(10);
+        let mut s = StatsAllocator::new(arena.alloc);
+        let (z, size) = s.alloc(0);
+        assert_eq!((z, size), (ZERO_SIZE_PTR, 0));
+        let (a, _) = s.alloc(20);
+        s.free(z);
+        let stats = s.stats();
+        assert_eq!((stats.allocs, stats.frees), (1, 0));
+        assert_eq!(stats.outstanding, 1);
+
+        // Growing a zero-sized pointer is its first real allocation
+        let (b, _) = s.realloc(z, 10);
+        let stats = s.stats();
+        assert_eq!(stats.allocs, 2);
+        assert_eq!(stats.outstanding, 2);
+        assert_eq!(stats.peak_outstanding, 2);
+        s.free(a);
+        s.free(b);
+        assert_eq!(s.stats().outstanding, 0);
+    }
+}
@@ -0,0 +1,101 @@
+// Memory from the host's heap for the memory managers' tests
+
+use std::ptr::set_memory;
+
+use kernel::mm::{Alloc, BuddyAlloc};
+use util::bitv::Bitv;
+
+/// `size` bytes of host memory at `start`, a multiple of `align`, freed
+/// when this is dropped.
+pub struct Region {
+    start: *mut u8,
+    size: uint,
+    priv memory: Vec<u8>
+}
+
+impl Region {
+    pub fn new(size: uint, align: uint) -> Region {
+        let mut memory = Vec::from_elem(size + align, 0u8);
+        let start = (memory.as_mut_ptr() as uint + align - 1) & !(align - 1);
+        Region { start: start as *mut u8, size: size, memory: memory }
+    }
+
+    pub fn end(&self) -> *mut u8 {
+        (self.start as uint + self.size) as *mut u8
+    }
+}
+
+/// An `Alloc` of `1 << order` leaves of `1 << min_block_shift` bytes, with
+/// its tree and its base, aligned to the arena's size, on the host's heap.
+pub struct Arena {
+    alloc: Alloc,
+    priv tree: Vec<u32>,
+    priv memory: Region
+}
+
+impl Arena {
+    pub fn new(order: uint, min_block_shift: uint) -> Arena {
+        let (bitv, tree) = Bitv::on_host(order);
+        let size = 1 << (order + min_block_shift);
+        let memory = Region::new(size, size);
+        let bytes = tree.len() * 4;
+        Arena {
+            alloc: Alloc::new(memory.start, order, min_block_shift, bitv, bytes),
+            tree: tree,
+            memory: memory
+        }
+    }
+
+    /// A byte-granular arena of `1 << order` bytes
+    pub fn bytes(order: uint) -> Arena {
+        Arena::new(order, 0)
+    }
+
+    pub fn base(&self) -> *mut u8 {
+        self.memory.start
+    }
+}
+
+/// A `BuddyAlloc` of `order` with its tree on the host's heap, which the
+/// returned vector owns.
+pub fn buddy(order: uint) -> (BuddyAlloc, Vec<u32>) {
+    let (bitv, tree) = Bitv::on_host(order);
+    let bytes = tree.len() * 4;
+    (BuddyAlloc::new(order, bitv, bytes, false), tree)
+}
+
+/// Byte `i` of the memory at `ptr`
+pub fn peek(ptr: *mut u8, i: uint) -> u8 {
+    unsafe { *((ptr as uint + i) as *u8) }
+}
+
+/// Set the `len` bytes at `ptr` to `byte`.
+pub fn fill(ptr: *mut u8, byte: u8, len: uint) {
+    unsafe { set_memory(ptr, byte, len); }
+}
+
+/// xorshift32, so that randomized tests can be replayed from their seed
+pub struct Rng {
+    priv state: u32
+}
+
+impl Rng {
+    /// `seed` must not be 0.
+    pub fn new(seed: u32) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// A number in `[0, n)`
+    pub fn below(&mut self, n: uint) -> uint {
+        self.next() as uint % n
+    }
+}
@@ -0,0 +1,445 @@
+/* Two-Level Segregated Fit allocator
+ * See http://www.gii.upv.es/tlsf/files/ecrts04_tlsf.pdf
+ */
+
+use core::mem::size_of;
+use core::ptr::copy_memory;
+#[cfg(target_word_size = "32")]
+use core::i32::{ctlz32, cttz32};
+#[cfg(target_word_size = "64")]
+use core::i64::{ctlz64, cttz64};
+use core::fail::assert;
+
+use kernel::mm::{Allocator, is_zero_size};
+
+#[cfg(target_word_size = "32")]
+static UINT_BITS: uint = 32;
+#[cfg(target_word_size = "64")]
+static UINT_BITS: uint = 64;
+
+// 16 second-level lists per first-level class
+static SL_LOG2: uint = 4;
+static SL_COUNT: uint = 1 << SL_LOG2;
+// Sizes below SMALL_BLOCK all share first-level class 0
+static FL_SHIFT: uint = SL_LOG2 + 2;
+static SMALL_BLOCK: uint = 1 << FL_SHIFT;
+static FL_COUNT: uint = UINT_BITS - FL_SHIFT + 1;
+
+static FREE: uint = 1;
+
+// Header in front of every block. `size` is the payload size with FREE in
+// bit 0; the free list links are only valid while the block is free and
+// overlap its payload.
+struct Block {
+    prev_phys: *mut Block,
+    size: uint,
+    next_free: *mut Block,
+    prev_free: *mut Block
+}
+
+/// Good-fit allocator with O(1) `alloc` and `free`. Free blocks are kept
+/// in lists segregated by size class, found through a first-level bitmap
+/// of power-of-two ranges and second-level bitmaps splitting each range
+/// into `SL_COUNT` classes. Neighboring free blocks are merged on `free`.
+pub struct TlsfAllocator {
+    priv start: *mut u8,
+    priv end: *mut u8,
+    priv fl_bitmap: uint,
+    priv sl_bitmap: [uint, ..FL_COUNT],
+    priv blocks: [[*mut Block, ..SL_COUNT], ..FL_COUNT]
+}
+
+#[inline]
+fn header() -> uint {
+    2 * size_of::<uint>()
+}
+
+// Smallest payload, which must fit the free list links
+#[inline]
+fn min_payload() -> uint {
+    2 * size_of::<uint>()
+}
+
+#[inline]
+fn round_up(x: uint, align: uint) -> uint {
+    (x + align - 1) & !(align - 1)
+}
+
+// index of the highest set bit
+#[cfg(target_word_size = "32")]
+#[inline]
+fn fls(x: uint) -> uint {
+    UINT_BITS - 1 - unsafe { ctlz32(x as i32) } as uint
+}
+
+#[cfg(target_word_size = "64")]
+#[inline]
+fn fls(x: uint) -> uint {
+    UINT_BITS - 1 - unsafe { ctlz64(x as i64) } as uint
+}
+
+// index of the lowest set bit
+#[cfg(target_word_size = "32")]
+#[inline]
+fn ffs(x: uint) -> uint {
+    unsafe { cttz32(x as i32) as uint }
+}
+
+#[cfg(target_word_size = "64")]
+#[inline]
+fn ffs(x: uint) -> uint {
+    unsafe { cttz64(x as i64) as uint }
+}
+
+// Size class of a block of `size` bytes
+fn mapping(size: uint) -> (uint, uint) {
+    if size < SMALL_BLOCK {
+        (0, size / (SMALL_BLOCK / SL_COUNT))
+    }
+    else {
+        let fl = fls(size);
+        ((fl - (FL_SHIFT - 1)), (size >> (fl - SL_LOG2)) ^ SL_COUNT)
+    }
+}
+
+impl Block {
+    #[inline]
+    fn payload(&self) -> uint {
+        self.size & !FREE
+    }
+
+    #[inline]
+    fn is_free(&self) -> bool {
+        self.size & FREE != 0
+    }
+
+    #[inline]
+    unsafe fn next_phys(&self) -> *mut Block {
+        (self as *Block as uint + header() + self.payload()) as *mut Block
+    }
+
+    #[inline]
+    fn ptr(&self) -> *mut u8 {
+        (self as *Block as uint + header()) as *mut u8
+    }
+}
+
+impl TlsfAllocator {
+    pub unsafe fn new(base: *mut u8, size: uint) -> TlsfAllocator {
+        let word = size_of::<uint>();
+        let start = round_up(base as uint, word);
+        let end = (base as uint + size) & !(word - 1);
+        let mut this = TlsfAllocator {
+            start: start as *mut u8,
+            end: end as *mut u8,
+            fl_bitmap: 0,
+            sl_bitmap: [0, ..FL_COUNT],
+            blocks: [[0 as *mut Block, ..SL_COUNT], ..FL_COUNT]
+        };
+
+        // One free block followed by an empty used block, so every block
+        // has a physical successor and the last one never merges past it.
+        if end >= start + 2 * header() + min_payload() {
+            let block = start as *mut Block;
+            (*block).prev_phys = 0 as *mut Block;
+            (*block).size = end - start - 2 * header();
+
+            let sentinel = (*block).next_phys();
+            (*sentinel).prev_phys = block;
+            (*sentinel).size = 0;
+
+            this.insert(block);
+        }
+        this
+    }
+
+    unsafe fn insert(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping((*block).payload());
+        let head = self.blocks[fl][sl];
+        (*block).size |= FREE;
+        (*block).prev_free = 0 as *mut Block;
+        (*block).next_free = head;
+        if head as uint != 0 {
+            (*head).prev_free = block;
+        }
+        self.blocks[fl][sl] = block;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping((*block).payload());
+        let prev = (*block).prev_free;
+        let next = (*block).next_free;
+        if next as uint != 0 {
+            (*next).prev_free = prev;
+        }
+        if prev as uint != 0 {
+            (*prev).next_free = next;
+        }
+        else {
+            self.blocks[fl][sl] = next;
+            if next as uint == 0 {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        (*block).size &= !FREE;
+    }
+
+    // A free block of at least `size` bytes, taken off its list
+    unsafe fn find(&mut self, size: uint) -> *mut Block {
+        // Round up to the next class so any block in it is large enough
+        let size = if size < SMALL_BLOCK {
+            size
+        }
+        else {
+            let round = (1 << (fls(size) - SL_LOG2)) - 1;
+            if size > !0u - round {
+                return 0 as *mut Block;
+            }
+            size + round
+        };
+        let (mut fl, sl) = mapping(size);
+        if fl >= FL_COUNT {
+            return 0 as *mut Block;
+        }
+
+        let mut sl_map = self.sl_bitmap[fl] & (!0u << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0u << (fl + 1));
+            if fl_map == 0 {
+                return 0 as *mut Block;
+            }
+            fl = ffs(fl_map);
+            sl_map = self.sl_bitmap[fl];
+        }
+
+        let block = self.blocks[fl][ffs(sl_map)];
+        self.remove(block);
+        block
+    }
+
+    // Cut `block` down to a payload of `size` bytes, freeing the rest if it
+    // is large enough to be a block.
+    unsafe fn trim(&mut self, block: *mut Block, size: uint) {
+        let payload = (*block).payload();
+        if payload < size + header() + min_payload() {
+            return;
+        }
+        let next = (*block).next_phys();
+        (*block).size = size;
+
+        let rest = (*block).next_phys();
+        (*rest).prev_phys = block;
+        (*rest).size = payload - size - header();
+        (*next).prev_phys = rest;
+        self.insert(rest);
+    }
+
+    // Split the free block `block` so that a new block starts `gap` bytes
+    // into its payload, returning the new block.
+    unsafe fn split_front(&mut self, block: *mut Block, gap: uint) -> *mut Block {
+        let next = (*block).next_phys();
+        let payload = (*block).payload();
+        (*block).size = gap - header();
+
+        let rest = (*block).next_phys();
+        (*rest).prev_phys = block;
+        (*rest).size = payload - gap;
+        (*next).prev_phys = rest;
+        self.insert(block);
+        rest
+    }
+
+    // Usable size of the allocated block at `ptr`
+    unsafe fn block_size(&self, ptr: *mut u8) -> uint {
+        (*((ptr as uint - header()) as *mut Block)).payload()
+    }
+}
+
+impl Allocator for TlsfAllocator {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        assert(align != 0 && align & (align - 1) == 0);
+        // Too close to the top of the address space to round up and pad
+        if size > !0u - align - header() - min_payload() {
+            return (self.start, 0);
+        }
+        let word = size_of::<uint>();
+        let size = round_up(if size < min_payload() { min_payload() } else { size }, word);
+
+        // Over-aligned requests leave room for a free block in front
+        let over = align > word;
+        let need = if over { size + align + header() + min_payload() } else { size };
+
+        let mut block = self.find(need);
+        if block as uint == 0 {
+            return (self.start, 0);
+        }
+
+        if over {
+            let ptr = (*block).ptr() as uint;
+            let mut aligned = round_up(ptr, align);
+            if aligned != ptr && aligned - ptr < header() + min_payload() {
+                aligned = round_up(ptr + header() + min_payload(), align);
+            }
+            if aligned != ptr {
+                block = self.split_front(block, aligned - ptr);
+            }
+        }
+
+        self.trim(block, size);
+        ((*block).ptr(), (*block).payload())
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 || is_zero_size(src) {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(src);
+            return (0 as *mut u8, 0);
+        }
+        let old_size = unsafe { self.block_size(src) };
+        if size <= old_size {
+            return (src, old_size);
+        }
+        match self.alloc(size) {
+            (ptr, 0) => (ptr, 0),
+            (ptr, sz) => {
+                unsafe { copy_memory(ptr, src as *u8, old_size); }
+                self.free(src);
+                (ptr, sz)
+            }
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        if is_zero_size(ptr) || (ptr as uint) < self.start as uint + header() || ptr >= self.end {
+            return;
+        }
+        unsafe {
+            let mut block = (ptr as uint - header()) as *mut Block;
+
+            let prev = (*block).prev_phys;
+            if prev as uint != 0 && (*prev).is_free() {
+                self.remove(prev);
+                (*prev).size += header() + (*block).payload();
+                block = prev;
+                (*(*block).next_phys()).prev_phys = block;
+            }
+
+            let next = (*block).next_phys();
+            if (*next).is_free() {
+                self.remove(next);
+                (*block).size += header() + (*next).payload();
+                (*(*block).next_phys()).prev_phys = block;
+            }
+
+            self.insert(block);
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        ptr >= self.start && ptr < self.end
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        if self.owns(ptr) { unsafe { self.block_size(ptr) } } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, TlsfAllocator, ZERO_SIZE_PTR};
+    use kernel::mm::testing::{Arena, Region};
+    use kernel::mm::tlsf::{fls, ffs, mapping, FL_SHIFT, FL_COUNT};
+
+    static SIZES: [uint, ..6] = [24, 100, 200, 700, 50, 3000];
+
+    // Allocate `SIZES` in turn into `ptrs` from `count` on, until `a` runs out,
+    // returning the new count.
+    fn fill<A: Allocator>(a: &mut A, ptrs: &mut [*mut u8], mut count: uint) -> uint {
+        while count < ptrs.len() {
+            match a.alloc(SIZES[count % SIZES.len()]) {
+                (_, 0) => break,
+                (ptr, _) => ptrs[count] = ptr
+            }
+            count += 1;
+        }
+        count
+    }
+
+    // Blocks of a mixed-size workload live in `a` at once: fill it, free every
+    // other block, then fill the holes again.
+    fn workload<A: Allocator>(a: &mut A) -> uint {
+        let mut ptrs = [0 as *mut u8, ..512];
+        let count = fill(a, &mut ptrs, 0);
+        let mut kept = 0;
+        for i in range(0, count) {
+            if i % 2 == 0 {
+                a.free(ptrs[i]);
+            }
+            else {
+                ptrs[kept] = ptrs[i];
+                kept += 1;
+            }
+        }
+        fill(a, &mut ptrs, kept)
+    }
+
+    #[test]
+    fn tlsf_fits_more_of_a_mixed_workload_than_buddy() {
+        let memory = Region::new(1 << 16, 16);
+        let mut tlsf = unsafe { TlsfAllocator::new(memory.start, 1 << 16) };
+        let mut buddy = Arena::bytes(16);
+        let tlsf_count = workload(&mut tlsf);
+        let buddy_count = workload(&mut buddy.alloc);
+        // Buddy rounds each of these sizes up to a power of two
+        assert!(tlsf_count > buddy_count);
+    }
+
+    #[test]
+    fn tlsf_aligns_and_merges_blocks() {
+        let memory = Region::new(4096, 16);
+        let mut tlsf = unsafe { TlsfAllocator::new(memory.start, 4096) };
+        let (p, _) = tlsf.alloc(100);
+        let (q, _) = unsafe { tlsf.alloc_align(64, 256) };
+        assert_eq!(q as uint & 255, 0);
+        let (r, _) = tlsf.alloc(100);
+        tlsf.free(p);
+        tlsf.free(q);
+        tlsf.free(r);
+        // Everything merged back into one block, which starts at `p`
+        let (big, size) = tlsf.alloc(2048);
+        assert_eq!(big as uint, p as uint);
+        assert!(size >= 2048);
+    }
+
+    #[test]
+    #[cfg(target_word_size = "64")]
+    fn size_classes_cover_the_whole_word() {
+        assert_eq!(fls(1 << 40), 40);
+        assert_eq!(ffs(1 << 40), 40);
+        assert_eq!(mapping((1 << 40) + (3 << 36)), (40 - (FL_SHIFT - 1), 3));
+        let (fl, _) = mapping(!0u);
+        assert_eq!(fl, FL_COUNT - 1);
+    }
+
+    #[test]
+    fn tlsf_realloc_of_a_zero_size_pointer_allocates() {
+        let memory = Region::new(4096, 16);
+        let mut tlsf = unsafe { TlsfAllocator::new(memory.start, 4096) };
+        let (p, size) = tlsf.realloc(ZERO_SIZE_PTR, 100);
+        assert!(size >= 100);
+        assert!(tlsf.owns(p));
+        tlsf.free(ZERO_SIZE_PTR);
+        // Requests that can't be rounded fail cleanly
+        for &request in [!0u, !0u - 8, !0u / 2].iter() {
+            let (_, size) = tlsf.alloc(request);
+            assert_eq!(size, 0);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use core::result::{Result, Ok, Err};
+
+use kernel::mm::{Allocator, AllocError};
+use util::fmt::Writer;
+use util::serial::Serial;
+
+/// Forwards every call to `inner` unchanged, logging it over serial, or to
+/// the writer given to `with_writer`, while tracing is enabled:
+///
+///     alloc 0x20 -> 0x102000 0x40
+///     realloc 0x102000 0x80 -> 0x102000 0x80
+///     free 0x102000
+///
+/// Sizes and pointers are in hex. Tracing starts disabled.
+pub struct TracingAllocator<A, W = Serial> {
+    priv inner: A,
+    priv out: W,
+    priv enabled: bool
+}
+
+fn put_hex<W: Writer>(w: &mut W, x: uint) {
+    w.puts("0x");
+    w.putuint(x, 16);
+}
+
+fn put_result<W: Writer>(w: &mut W, r: (*mut u8, uint)) {
+    let (ptr, size) = r;
+    w.puts(" -> ");
+    put_hex(w, ptr as uint);
+    w.putc(' ' as u8);
+    put_hex(w, size);
+    w.putc('\n' as u8);
+}
+
+impl<A: Allocator> TracingAllocator<A> {
+    pub fn new(inner: A) -> TracingAllocator<A> {
+        TracingAllocator::with_writer(inner, Serial)
+    }
+}
+
+impl<A: Allocator, W: Writer> TracingAllocator<A, W> {
+    /// Log to `out` rather than over serial.
+    pub fn with_writer(inner: A, out: W) -> TracingAllocator<A, W> {
+        TracingAllocator { inner: inner, out: out, enabled: false }
+    }
+
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn inner<'a>(&'a mut self) -> &'a mut A {
+        &mut self.inner
+    }
+
+    pub fn writer<'a>(&'a mut self) -> &'a mut W {
+        &mut self.out
+    }
+}
+
+impl<A: Allocator, W: Writer> Allocator for TracingAllocator<A, W> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        let r = self.inner.alloc_align(size, align);
+        if self.enabled {
+            let w = &mut self.out;
+            w.puts("alloc ");
+            put_hex(w, size);
+            if align > 1 {
+                w.puts(" align ");
+                put_hex(w, align);
+            }
+            put_result(w, r);
+        }
+        r
+    }
+
+    fn try_alloc(&mut self, size: uint) -> Result<(*mut u8, uint), AllocError> {
+        let r = self.inner.try_alloc(size);
+        if self.enabled {
+            let w = &mut self.out;
+            w.puts("try_alloc ");
+            put_hex(w, size);
+            match r {
+                Ok(r) => put_result(w, r),
+                Err(_) => w.puts(" -> error\n")
+            }
+        }
+        r
+    }
+
+    fn zero_alloc(&mut self, size: uint) -> (*mut u8, uint) {
+        let r = self.inner.zero_alloc(size);
+        if self.enabled {
+            let w = &mut self.out;
+            w.puts("zero_alloc ");
+            put_hex(w, size);
+            put_result(w, r);
+        }
+        r
+    }
+
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        let r = self.inner.realloc(src, size);
+        if self.enabled {
+            let w = &mut self.out;
+            w.puts("realloc ");
+            put_hex(w, src as uint);
+            w.putc(' ' as u8);
+            put_hex(w, size);
+            put_result(w, r);
+        }
+        r
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        self.inner.free(ptr);
+        if self.enabled {
+            let w = &mut self.out;
+            w.puts("free ");
+            put_hex(w, ptr as uint);
+            w.putc('\n' as u8);
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        self.inner.owns(ptr)
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        self.inner.usable_size(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::from_utf8;
+    use kernel::mm::{Allocator, TracingAllocator};
+    use kernel::mm::testing::Arena;
+    use util::fmt::Buffer;
+
+    #[test]
+    fn tracing_records_the_calls() {
+        let arena = Arena::bytes(10);
+        let mut log = [0u8, ..256];
+        let (a, b, len) = {
+            let mut t = TracingAllocator::with_writer(arena.alloc, Buffer::new(&mut log));
+            // Nothing is logged until tracing is turned on
+            t.alloc(16);
+            t.set_tracing(true);
+            let (a, _) = t.alloc(32);
+            let (b, _) = t.realloc(a, 64);
+            t.free(b);
+            (a as uint, b as uint, t.writer().len())
+        };
+        let expected = format!("alloc 0x20 -> 0x{:x} 0x20\nrealloc 0x{:x} 0x40 -> 0x{:x} 0x40\nfree 0x{:x}\n",
+                               a, a, b, b);
+        assert_eq!(from_utf8(log.slice_to(len)).unwrap(), expected.as_slice());
+    }
+}
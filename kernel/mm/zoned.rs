@@ -0,0 +1,172 @@
+use core::fail::assert;
+use core::option::{Option, Some, None};
+
+use kernel::mm::Allocator;
+
+/// Zones a `ZonedAllocator` can try in order
+pub static MAX_ZONES: uint = 4;
+
+/// Spreads allocations over allocators for disjoint memory regions, such
+/// as the DMA, normal and high zones of physical memory. Allocations try
+/// the zones in the preferred order; zones left out of it are only used
+/// through `alloc_from`, which keeps them for requests that need them.
+/// Frees go to whichever zone owns the pointer.
+pub struct ZonedAllocator<'a, A> {
+    priv zones: &'a mut [A],
+    priv preference: [uint, ..MAX_ZONES],
+    priv preferred: uint
+}
+
+impl<'a, A: Allocator> ZonedAllocator<'a, A> {
+    /// Manage `zones`, at most `MAX_ZONES` of them, listed from low to
+    /// high addresses. Until `set_preference`, the highest zone is tried
+    /// first, so low memory is used last.
+    pub fn new(zones: &'a mut [A]) -> ZonedAllocator<'a, A> {
+        let count = zones.len();
+        assert(count <= MAX_ZONES);
+        let mut preference = [0, ..MAX_ZONES];
+        let mut i = 0;
+        while i < count {
+            preference[i] = count - 1 - i;
+            i += 1;
+        }
+        ZonedAllocator { zones: zones, preference: preference, preferred: count }
+    }
+
+    /// Try the zones in `order` for allocations, which lists zone indices.
+    pub fn set_preference(&mut self, order: &[uint]) {
+        assert(order.len() <= MAX_ZONES);
+        let mut i = 0;
+        while i < order.len() {
+            assert(order[i] < self.zones.len());
+            self.preference[i] = order[i];
+            i += 1;
+        }
+        self.preferred = order.len();
+    }
+
+    pub fn zone<'b>(&'b mut self, zone: uint) -> &'b mut A {
+        &mut self.zones[zone]
+    }
+
+    /// Allocate from `zone` only, whatever the preference.
+    pub unsafe fn alloc_from(&mut self, zone: uint, size: uint, align: uint) -> (*mut u8, uint) {
+        self.zones[zone].alloc_align(size, align)
+    }
+
+    // Index of the zone holding `ptr`
+    fn zone_of(&self, ptr: *mut u8) -> Option<uint> {
+        let mut i = 0;
+        while i < self.zones.len() {
+            if self.zones[i].owns(ptr) {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+impl<'a, A: Allocator> Allocator for ZonedAllocator<'a, A> {
+    unsafe fn alloc_align(&mut self, size: uint, align: uint) -> (*mut u8, uint) {
+        let mut i = 0;
+        while i < self.preferred {
+            match self.zones[self.preference[i]].alloc_align(size, align) {
+                (_, 0) => {}
+                r => return r
+            }
+            i += 1;
+        }
+        (0 as *mut u8, 0)
+    }
+
+    // A block stays in its zone; if the zone can't resize it, realloc
+    // fails.
+    fn realloc(&mut self, src: *mut u8, size: uint) -> (*mut u8, uint) {
+        if src as uint == 0 {
+            return self.alloc(size);
+        }
+        match self.zone_of(src) {
+            Some(zone) => self.zones[zone].realloc(src, size),
+            None => (0 as *mut u8, 0)
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        match self.zone_of(ptr) {
+            Some(zone) => self.zones[zone].free(ptr),
+            None => {}
+        }
+    }
+
+    fn owns(&self, ptr: *mut u8) -> bool {
+        match self.zone_of(ptr) {
+            Some(_) => true,
+            None => false
+        }
+    }
+
+    fn usable_size(&self, ptr: *mut u8) -> uint {
+        match self.zone_of(ptr) {
+            Some(zone) => self.zones[zone].usable_size(ptr),
+            None => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::mm::{Allocator, ZonedAllocator};
+    use kernel::mm::testing::Arena;
+
+    #[test]
+    fn zoned_prefers_high_memory_and_routes_frees() {
+        let dma = Arena::bytes(6);
+        let normal = Arena::bytes(8);
+        let high = Arena::bytes(8);
+        let mut zones = [dma.alloc, normal.alloc, high.alloc];
+        let mut z = ZonedAllocator::new(&mut zones);
+
+        let (p, _) = z.alloc(256);
+        assert!(z.zone(2).owns(p));
+        let (q, _) = z.alloc(128);
+        assert!(z.zone(1).owns(q));
+        let (r, _) = z.alloc(128);
+        assert!(z.zone(1).owns(r));
+        // DMA memory is only used once everything above it is full
+        let (s, _) = z.alloc(64);
+        assert!(z.zone(0).owns(s));
+        let (_, size) = z.alloc(1);
+        assert_eq!(size, 0);
+
+        z.free(q);
+        assert_eq!(z.zone(1).used_bytes(), 128);
+        z.free(s);
+        assert_eq!(z.zone(0).used_bytes(), 0);
+        z.free(p);
+        assert_eq!(z.zone(2).used_bytes(), 0);
+        z.free(r);
+        assert_eq!(z.zone(1).used_bytes(), 0);
+    }
+
+    #[test]
+    fn zoned_keeps_zones_left_out_of_the_preference() {
+        let dma = Arena::bytes(6);
+        let normal = Arena::bytes(6);
+        let mut zones = [dma.alloc, normal.alloc];
+        let mut z = ZonedAllocator::new(&mut zones);
+        z.set_preference(&[1]);
+
+        let (p, _) = z.alloc(64);
+        assert!(z.zone(1).owns(p));
+        let (_, size) = z.alloc(1);
+        assert_eq!(size, 0);
+        let (q, _) = unsafe { z.alloc_from(0, 16, 1) };
+        assert!(z.zone(0).owns(q));
+
+        z.free(q);
+        z.free(p);
+        assert_eq!(z.zone(0).used_bytes(), 0);
+        assert_eq!(z.zone(1).used_bytes(), 0);
+    }
+}
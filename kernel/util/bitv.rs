@@ -1,28 +1,121 @@
-use core::mem::transmute;
 use core::ptr::set_memory;
+use core::fail::abort;
+use core::i32::cttz32;
+use core::option::{Option, Some, None};
+use core::iter::Iterator;
 use util::ptr::mut_offset;
 
 // vector of 2-bit
 pub struct Bitv {
-    storage: *mut u32
+    storage: *mut u32,
+    // entries `storage` was cleared for; indices past it abort in debug
+    // builds
+    nodes: uint
+}
+
+/// Entries of a `Bitv` in index order as (index, value), reading each word
+/// of storage once.
+pub struct Nodes<'a> {
+    priv bitv: &'a Bitv,
+    priv index: uint,
+    priv word: u32
+}
+
+// entry `i` of the word holding it
+#[inline]
+fn decode(word: u32, i: uint) -> u8 {
+    (word >> ((i % 16) * 2)) as u8 & 3
 }
 
 impl Bitv {
+    /// Nodes in a buddy tree of `order`, `2^(order+1) - 1`
+    pub fn nodes_for_order(order: uint) -> uint {
+        (1 << (order + 1)) - 1
+    }
+
+    /// Bytes of storage for the nodes of a buddy tree of `order`, rounded
+    /// up to whole words.
+    pub fn bytes_for_order(order: uint) -> uint {
+        (Bitv::nodes_for_order(order) + 15) / 16 * 4
+    }
+
+    /// A zeroed `Bitv` for a tree of `order` in `storage`, which must hold
+    /// `bytes_for_order(order)` bytes.
+    pub fn from_storage(storage: *mut u32, order: uint) -> Bitv {
+        let mut bitv = Bitv { storage: storage, nodes: 0 };
+        bitv.clear(Bitv::nodes_for_order(order));
+        bitv
+    }
+
+    /// A zeroed `Bitv` for a tree of `order` with its storage on the host's
+    /// heap, for tests. The storage is the returned vector, which must
+    /// outlive the `Bitv`.
+    #[cfg(test)]
+    pub fn on_host(order: uint) -> (Bitv, Vec<u32>) {
+        let mut words = Vec::from_elem(Bitv::bytes_for_order(order) / 4, 0u32);
+        let bitv = Bitv::from_storage(words.as_mut_ptr(), order);
+        (bitv, words)
+    }
+
     #[inline]
     pub fn get(&self, i: uint) -> u8 {
+        self.check(i);
         let w = (i / 16) as int;
-        let b = (i % 16) * 2;
         unsafe {
-            transmute((*mut_offset(self.storage, w) as uint >> b) as u8 & 3)
+            decode(*mut_offset(self.storage, w), i)
         }
     }
 
     #[inline]
     pub fn set(&self, i: uint, x: u8) {
+        self.check(i);
         let w = (i / 16) as int;
         let b = (i % 16) * 2;
         unsafe {
-            *mut_offset(self.storage, w) &=  !(3 << b) | (x as u32 << b)
+            let p = mut_offset(self.storage, w);
+            *p = (*p & !(3 << b)) | (x as u32 << b);
+        }
+    }
+
+    /// Index of the first entry in `[from, to)` equal to `x`, testing a
+    /// word of 16 entries at a time, so words without a match are skipped
+    /// at once. A `Bitv` doesn't know the tree's shape: entries under a
+    /// used or unused node match like live ones, and callers check what
+    /// they find.
+    pub fn find_first(&self, x: u8, from: uint, to: uint) -> Option<uint> {
+        if from >= to {
+            return None;
+        }
+        self.check(to - 1);
+        let pattern = (x as u32 & 3) * 0x55555555;
+        let last = (to - 1) / 16;
+        let mut w = from / 16;
+        while w <= last {
+            // An entry is 0 after the xor exactly where it matches
+            let t = unsafe { *mut_offset(self.storage, w as int) } ^ pattern;
+            let mut hits = !(t | (t >> 1)) & 0x55555555;
+            if w == from / 16 {
+                hits &= !0 << ((from % 16) * 2);
+            }
+            if w == last && to % 16 != 0 {
+                hits &= (1 << ((to % 16) * 2)) - 1;
+            }
+            if hits != 0 {
+                return Some(w * 16 + unsafe { cttz32(hits as i32) } as uint / 2);
+            }
+            w += 1;
+        }
+        None
+    }
+
+    pub fn iter_nodes<'a>(&'a self) -> Nodes<'a> {
+        Nodes { bitv: self, index: 0, word: 0 }
+    }
+
+    #[inline]
+    fn check(&self, i: uint) {
+        if cfg!(not(ndebug)) && i >= self.nodes {
+            abort();
         }
     }
 
@@ -31,9 +124,100 @@ impl Bitv {
         self.storage as *mut u8
     }
 
-    pub fn clear(&self, capacity: uint) {
+    pub fn clear(&mut self, capacity: uint) {
+        self.nodes = capacity;
         unsafe {
-            set_memory(self.as_mut_ptr(), 0, capacity / 4);
+            set_memory(self.as_mut_ptr(), 0, (capacity + 15) / 16 * 4);
+        }
+    }
+}
+
+impl<'a> Iterator<(uint, u8)> for Nodes<'a> {
+    fn next(&mut self) -> Option<(uint, u8)> {
+        let i = self.index;
+        if i >= self.bitv.nodes {
+            return None;
+        }
+        if i % 16 == 0 {
+            self.word = unsafe { *mut_offset(self.bitv.storage, (i / 16) as int) };
+        }
+        self.index += 1;
+        Some((i, decode(self.word, i)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use util::bitv::Bitv;
+
+    #[test]
+    fn host_storage_starts_zeroed() {
+        let (bitv, words) = Bitv::on_host(5);
+        assert_eq!(words.len(), 4);
+        for (_, x) in bitv.iter_nodes() {
+            assert_eq!(x, 0);
+        }
+    }
+
+    #[test]
+    fn set_and_get_round_trip() {
+        let (bitv, _words) = Bitv::on_host(5);
+        let mut i = 0;
+        while i < Bitv::nodes_for_order(5) {
+            bitv.set(i, (i % 4) as u8);
+            i += 1;
+        }
+        for (i, x) in bitv.iter_nodes() {
+            assert_eq!(x, (i % 4) as u8);
+        }
+        // Setting one entry leaves its neighbours in the word alone
+        bitv.set(17, 0);
+        assert_eq!(bitv.get(16), 0);
+        assert_eq!(bitv.get(17), 0);
+        assert_eq!(bitv.get(18), 2);
+    }
+
+    #[test]
+    #[should_fail]
+    fn get_past_the_last_node_aborts() {
+        let (bitv, _words) = Bitv::on_host(5);
+        bitv.get(Bitv::nodes_for_order(5));
+    }
+
+    #[test]
+    #[should_fail]
+    fn set_past_the_last_node_aborts() {
+        let (bitv, _words) = Bitv::on_host(5);
+        bitv.set(Bitv::nodes_for_order(5), 1);
+    }
+
+    #[test]
+    fn find_first_picks_a_lone_match_out_of_many() {
+        let (bitv, _words) = Bitv::on_host(6);
+        let nodes = Bitv::nodes_for_order(6);
+        for i in range(0u, nodes) {
+            bitv.set(i, 1);
+        }
+        assert_eq!(bitv.find_first(0, 0, nodes), None);
+        bitv.set(93, 0);
+        assert_eq!(bitv.find_first(0, 0, nodes), Some(93));
+        assert_eq!(bitv.find_first(0, 63, nodes), Some(93));
+        // The bounds hold inside a word as well as across words
+        assert_eq!(bitv.find_first(0, 94, nodes), None);
+        assert_eq!(bitv.find_first(0, 80, 93), None);
+        assert_eq!(bitv.find_first(0, 93, 94), Some(93));
+        assert_eq!(bitv.find_first(1, 93, nodes), Some(94));
+    }
+
+    #[test]
+    fn bytes_for_order_covers_two_bits_a_node() {
+        for order in range(0u, 24) {
+            let bits = Bitv::nodes_for_order(order) * 2;
+            let bytes = (bits + 7) / 8;
+            // rounded up to whole words
+            assert_eq!(Bitv::bytes_for_order(order), (bytes + 3) / 4 * 4);
         }
+        assert_eq!(Bitv::bytes_for_order(0), 4);
+        assert_eq!(Bitv::bytes_for_order(4), 8);
     }
 }
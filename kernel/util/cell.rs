@@ -0,0 +1,21 @@
+/// A value that is mutated through `&self`, for types such as
+/// `LockedAllocator` that serialize access to it themselves. `get` is the
+/// only way in, so every write under a shared borrow goes through a raw
+/// pointer at its use rather than a `&mut` conjured from `&`. The field is
+/// public so that statics can build one.
+pub struct SharedCell<T> {
+    value: T
+}
+
+impl<T> SharedCell<T> {
+    pub fn new(value: T) -> SharedCell<T> {
+        SharedCell { value: value }
+    }
+
+    /// Pointer to the value. The caller must make sure nothing else
+    /// reaches the value while it is used.
+    #[inline]
+    pub fn get(&self) -> *mut T {
+        &self.value as *T as *mut T
+    }
+}
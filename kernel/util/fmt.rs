@@ -0,0 +1,50 @@
+use core::slice;
+use core::str::as_bytes;
+
+use util::int;
+
+/// Byte sink for `Show`.
+pub trait Writer {
+    fn putc(&mut self, c: u8);
+
+    fn puts(&mut self, msg: &str) {
+        for c in slice::iter(as_bytes(msg)) {
+            self.putc(*c);
+        }
+    }
+
+    fn putuint(&mut self, num: uint, radix: uint) {
+        int::to_str_bytes(num as int, radix, |c| self.putc(c));
+    }
+}
+
+/// Types that can describe themselves to a `Writer`.
+pub trait Show {
+    fn fmt<W: Writer>(&self, w: &mut W);
+}
+
+/// Writes into a byte buffer, dropping whatever doesn't fit.
+pub struct Buffer<'a> {
+    priv buf: &'a mut [u8],
+    priv len: uint
+}
+
+impl<'a> Buffer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Buffer<'a> {
+        Buffer { buf: buf, len: 0 }
+    }
+
+    /// Bytes written so far
+    pub fn len(&self) -> uint {
+        self.len
+    }
+}
+
+impl<'a> Writer for Buffer<'a> {
+    fn putc(&mut self, c: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = c;
+            self.len += 1;
+        }
+    }
+}
@@ -1,6 +1,9 @@
 pub mod int;
 pub mod ptr;
+pub mod cell;
 pub mod bitv;
+pub mod fmt;
+pub mod serial;
 
 #[cfg(target_word_size = "32")]
 pub mod rt;
@@ -0,0 +1,14 @@
+use platform::io;
+use util::fmt::Writer;
+
+/// The serial console. `Writer` supplies `puts` and `putuint` on top of
+/// its `putc`.
+pub struct Serial;
+
+impl Writer for Serial {
+    fn putc(&mut self, c: u8) {
+        unsafe {
+            io::write_char(c as char, io::UART0);
+        }
+    }
+}
@@ -6,7 +6,7 @@
 #[crate_type = "lib"];
 // Forked from pczarn/rustboot
 #[no_std];
-#[feature(asm, globs, macro_rules, default_type_params, phase)];
+#[feature(asm, globs, macro_rules, default_type_params, phase, unsafe_destructor)];
 
 #[phase(syntax, link)]
 extern crate core;
@@ -16,8 +16,9 @@ pub use support::{memcpy, memmove};
 
 use kernel::util;
 use platform::{cpu, io};
-pub mod kernel;
+// Before `kernel`, which uses the macros
 mod macros;
+pub mod kernel;
 
 #[cfg(target_arch = "arm")]
 #[path = "rust-core/support.rs"]
@@ -158,3 +158,37 @@ macro_rules! impl_ops (
         }
     )
 )
+
+// An empty `BuddyAlloc` of `order` over the tree at `storage`, for
+// statics, which can only be built from struct literals. It matches
+// `BuddyAlloc::new` without clearing the tree, which has to happen at run
+// time; keeping the literal here means a new field breaks one place.
+macro_rules! buddy_alloc_static (
+    ($order:expr, $storage:expr) => (
+        ::kernel::mm::allocator::BuddyAlloc {
+            order: $order,
+            tree: ::kernel::util::bitv::Bitv { storage: $storage as *mut u32, nodes: 0 },
+            used: 0,
+            peak_used: 0,
+            alloc_count: 0,
+            poison_on_free: false,
+            zero_on_free: false,
+            reserved: [(0, 0), ..::kernel::mm::allocator::MAX_RESERVED],
+            reserved_count: 0,
+            pinned: [0, ..::kernel::mm::allocator::MAX_PINNED],
+            pinned_count: 0,
+            on_alloc: None,
+            on_free: None,
+            event_log: 0 as *mut ::kernel::mm::allocator::AllocEvent,
+            event_capacity: 0,
+            event_count: 0,
+            free_levels: !0,
+            split_policy: ::kernel::mm::allocator::LeftFirst,
+            fresh_from: !0,
+            links: 0 as *mut u8,
+            link_shift: 0,
+            list_from: 0,
+            free_heads: [::kernel::mm::allocator::NIL, ..::kernel::mm::allocator::MAX_LEVELS]
+        }
+    )
+)
@@ -0,0 +1,25 @@
+/* test.rs */
+
+// Host build of the memory managers for `make test arch=host`. The kernel
+// modules are compiled against std, with arch/host standing in for
+// rust-core and the hardware they import. `mm::physical` and
+// `mm::virtual` drive the board's MMU, so they are left out.
+#[crate_id = "test#0.1"];
+#[feature(globs, macro_rules, default_type_params, unsafe_destructor)];
+
+use kernel::util;
+use platform::{cpu, io};
+
+#[path = "arch/host/core.rs"]
+mod core;
+
+#[path = "arch/host/"]
+mod platform {
+    pub mod cpu;
+    pub mod io;
+}
+
+pub mod kernel {
+    pub mod util;
+    pub mod mm;
+}